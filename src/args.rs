@@ -24,4 +24,8 @@ pub struct Args {
 
     #[arg(short, long, default_value = "static")]
     pub website: PathBuf,
+
+    /// Public URL of the dashboard, used for links in notifications
+    #[arg(long)]
+    pub dashboard_url: Option<String>,
 }
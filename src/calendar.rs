@@ -0,0 +1,81 @@
+use std::fmt;
+
+use chrono::{DateTime, Datelike, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::model::ActiveHoursDescriptor;
+
+/// A cron expression with five (or six, including seconds) fields. It is
+/// parsed when the config is loaded, so invalid expressions are rejected at
+/// startup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub struct CronExpression {
+    source: String,
+    cron: Box<Cron>,
+}
+
+impl CronExpression {
+    pub fn parse(source: &str) -> Result<CronExpression, String> {
+        let cron = Cron::new(source)
+            .with_seconds_optional()
+            .parse()
+            .map_err(|error| format!("Invalid cron expression {}: {}", source, error))?;
+        Ok(CronExpression {
+            source: source.to_string(),
+            cron: Box::new(cron),
+        })
+    }
+
+    /// The first occurrence after `after`, evaluated in `timezone`.
+    pub fn next_after(&self, after: DateTime<Utc>, timezone: &Tz) -> Option<DateTime<Utc>> {
+        self.cron
+            .find_next_occurrence(&after.with_timezone(timezone), false)
+            .ok()
+            .map(|next| next.with_timezone(&Utc))
+    }
+}
+
+impl TryFrom<String> for CronExpression {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        CronExpression::parse(&source)
+    }
+}
+
+impl From<CronExpression> for String {
+    fn from(expression: CronExpression) -> Self {
+        expression.source
+    }
+}
+
+impl fmt::Display for CronExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Whether `at` lies within the active hours, evaluated in `timezone`.
+pub fn in_active_hours(
+    active_hours: &ActiveHoursDescriptor,
+    at: DateTime<Utc>,
+    timezone: &Tz,
+) -> bool {
+    let at = at.with_timezone(timezone);
+    let time = at.time();
+    let (start, end) = (active_hours.start, active_hours.end);
+    // The day on which the current range started.
+    let day = if start <= end {
+        (start <= time && time < end).then_some(at.weekday())
+    } else if time >= start {
+        Some(at.weekday())
+    } else if time < end {
+        Some(at.weekday().pred())
+    } else {
+        None
+    };
+    day.is_some_and(|day| active_hours.days.is_empty() || active_hours.days.contains(&day))
+}
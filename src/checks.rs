@@ -1,33 +1,43 @@
-use crate::model::{CheckedMonitoringTargetStatus, MonitoringTargetStatus};
+use crate::model::{
+    CheckedMonitoringTargetStatus, MetricThresholdDescriptor, MonitoringTargetStatus,
+};
 use dns_lookup::lookup_host;
-use std::{process::Command, sync::Arc, time::Duration};
+use std::{collections::HashMap, process::Command, sync::Arc, time::Duration};
 use systemstat::{Platform, System};
 
-pub async fn check_systemd_unit(unit: &str) -> CheckedMonitoringTargetStatus {
+fn check_systemd_unit_result(unit: &str) -> Result<CheckedMonitoringTargetStatus, std::io::Error> {
     let exit_status = Command::new("systemctl")
         .arg("is-active")
         .arg(unit)
         .arg("-q")
-        .output()
-        .expect("Failed to execute command")
+        .output()?
         .status;
 
     if exit_status.success() {
-        CheckedMonitoringTargetStatus {
+        Ok(CheckedMonitoringTargetStatus {
             status: MonitoringTargetStatus::Healthy,
             description: "".to_string(),
-        }
+            metrics: HashMap::new(),
+        })
     } else {
-        let output = Command::new("systemctl")
-            .arg("status")
-            .arg(unit)
-            .output()
-            .expect("Failed to execute command");
-        let output = String::from_utf8(output.stdout).unwrap();
-        CheckedMonitoringTargetStatus {
+        let output = Command::new("systemctl").arg("status").arg(unit).output()?;
+        let output = String::from_utf8_lossy(&output.stdout).to_string();
+        Ok(CheckedMonitoringTargetStatus {
             status: MonitoringTargetStatus::Unhealthy,
             description: output,
-        }
+            metrics: HashMap::new(),
+        })
+    }
+}
+
+pub async fn check_systemd_unit(unit: &str) -> CheckedMonitoringTargetStatus {
+    match check_systemd_unit_result(unit) {
+        Ok(status) => status,
+        Err(error) => CheckedMonitoringTargetStatus {
+            status: MonitoringTargetStatus::Unknown,
+            description: format!("Failed to run systemctl: {}", error),
+            metrics: HashMap::new(),
+        },
     }
 }
 
@@ -35,16 +45,19 @@ async fn check_http_url_result(url: &str) -> Result<CheckedMonitoringTargetStatu
     let client = reqwest::Client::new();
     let response = client.get(url).send().await?;
     let status_code = response.status().as_u16();
+    let metrics = HashMap::from([("status_code".to_string(), status_code as f64)]);
 
     if response.status().is_success() {
         Ok(CheckedMonitoringTargetStatus {
             status: MonitoringTargetStatus::Healthy,
             description: "".to_string(),
+            metrics,
         })
     } else {
         Ok(CheckedMonitoringTargetStatus {
             status: MonitoringTargetStatus::Unhealthy,
             description: format!("Status code: {}", status_code),
+            metrics,
         })
     }
 }
@@ -55,6 +68,7 @@ pub async fn check_http_url(url: &str) -> CheckedMonitoringTargetStatus {
         Err(error) => CheckedMonitoringTargetStatus {
             status: MonitoringTargetStatus::Unhealthy,
             description: error.to_string(),
+            metrics: HashMap::new(),
         },
     }
 }
@@ -69,6 +83,7 @@ pub async fn check_fs_space(path: &str) -> CheckedMonitoringTargetStatus {
             return CheckedMonitoringTargetStatus {
                 status: MonitoringTargetStatus::Unhealthy,
                 description: format!("Mount point not found: {}", path),
+                metrics: HashMap::new(),
             }
         }
     };
@@ -82,6 +97,7 @@ pub async fn check_fs_space(path: &str) -> CheckedMonitoringTargetStatus {
     CheckedMonitoringTargetStatus {
         status,
         description: format!("Disk space usage: {}%", percentage),
+        metrics: HashMap::from([("disk_usage_percent".to_string(), percentage as f64)]),
     }
 }
 
@@ -94,6 +110,7 @@ async fn check_ping_result(
             return Ok(CheckedMonitoringTargetStatus {
                 status: MonitoringTargetStatus::Unhealthy,
                 description: format!("Failed to resolve host: {}", address),
+                metrics: HashMap::new(),
             });
         }
     };
@@ -101,6 +118,7 @@ async fn check_ping_result(
         return Ok(CheckedMonitoringTargetStatus {
             status: MonitoringTargetStatus::Unhealthy,
             description: format!("No IP addresses found for host: {}", address),
+            metrics: HashMap::new(),
         });
     }
     let addr = ips[0];
@@ -117,6 +135,7 @@ async fn check_ping_result(
     Ok(CheckedMonitoringTargetStatus {
         status: MonitoringTargetStatus::Healthy,
         description: format!("{} ms", ping),
+        metrics: HashMap::from([("rtt_ms".to_string(), ping as f64)]),
     })
 }
 
@@ -137,6 +156,161 @@ pub async fn check_ping(address: &str) -> CheckedMonitoringTargetStatus {
         Err(error) => CheckedMonitoringTargetStatus {
             status: MonitoringTargetStatus::Unhealthy,
             description: format_ping_error(error),
+            metrics: HashMap::new(),
+        },
+    }
+}
+
+/// A sample of the Prometheus text format.
+struct PrometheusSample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+/// Parses a sample line of the Prometheus text format. Comments and malformed
+/// lines are skipped.
+fn parse_prometheus_sample(line: &str) -> Option<PrometheusSample> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+    let mut labels = vec![];
+    if let Some(label_text) = rest.strip_prefix('{') {
+        let mut chars = label_text.chars();
+        loop {
+            let remaining = chars.as_str().trim_start_matches([',', ' ']);
+            if let Some(after_labels) = remaining.strip_prefix('}') {
+                rest = after_labels;
+                break;
+            }
+            chars = remaining.chars();
+            let label_name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+            if chars.next() != Some('"') {
+                return None;
+            }
+            let mut label_value = String::new();
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => match chars.next()? {
+                        'n' => label_value.push('\n'),
+                        c => label_value.push(c),
+                    },
+                    c => label_value.push(c),
+                }
+            }
+            labels.push((label_name.trim().to_string(), label_value));
+        }
+    }
+    let value = rest.split_whitespace().next()?.parse().ok()?;
+    Some(PrometheusSample {
+        name,
+        labels,
+        value,
+    })
+}
+
+/// Name of a sample as in the text format, e.g. `queue_depth{queue="mail"}`.
+fn sample_name(name: &str, labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+async fn check_prometheus_metric_result(
+    url: &str,
+    metric: &str,
+    label_matchers: &HashMap<String, String>,
+    thresholds: &[MetricThresholdDescriptor],
+) -> Result<CheckedMonitoringTargetStatus, reqwest::Error> {
+    let client = reqwest::Client::new();
+    let body = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let samples: Vec<(String, f64)> = body
+        .lines()
+        .filter_map(parse_prometheus_sample)
+        .filter(|sample| {
+            sample.name == metric
+                && label_matchers.iter().all(|(matcher_name, matcher_value)| {
+                    sample
+                        .labels
+                        .iter()
+                        .any(|(name, value)| name == matcher_name && value == matcher_value)
+                })
+        })
+        .map(|sample| (sample_name(&sample.name, &sample.labels), sample.value))
+        .collect();
+    if samples.is_empty() {
+        return Ok(CheckedMonitoringTargetStatus {
+            status: MonitoringTargetStatus::Unhealthy,
+            description: format!("No sample of {} found", metric),
+            metrics: HashMap::new(),
+        });
+    }
+    let mut status = MonitoringTargetStatus::Healthy;
+    let mut descriptions = vec![];
+    for (name, value) in samples.iter() {
+        let matched = thresholds
+            .iter()
+            .filter(|threshold| threshold.operator.compare(*value, threshold.value))
+            .max_by_key(|threshold| threshold.status.severity());
+        match matched {
+            Some(threshold) => {
+                descriptions.push(format!(
+                    "{} is {} ({} {})",
+                    name, value, threshold.operator, threshold.value
+                ));
+                if threshold.status.severity() > status.severity() {
+                    status = threshold.status.clone();
+                }
+            }
+            None => descriptions.push(format!("{} is {}", name, value)),
+        }
+    }
+    Ok(CheckedMonitoringTargetStatus {
+        status,
+        description: descriptions.join("\n"),
+        // Infinite and NaN samples still count against the thresholds, but
+        // JSON, in which metrics are stored and sent, cannot represent them.
+        metrics: samples
+            .into_iter()
+            .filter(|(_, value)| value.is_finite())
+            .collect(),
+    })
+}
+
+pub async fn check_prometheus_metric(
+    url: &str,
+    metric: &str,
+    labels: &HashMap<String, String>,
+    thresholds: &[MetricThresholdDescriptor],
+) -> CheckedMonitoringTargetStatus {
+    match check_prometheus_metric_result(url, metric, labels, thresholds).await {
+        Ok(status) => status,
+        Err(error) => CheckedMonitoringTargetStatus {
+            status: MonitoringTargetStatus::Unhealthy,
+            description: error.to_string(),
+            metrics: HashMap::new(),
         },
     }
 }
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use rusqlite::{Connection, Result};
+
+use crate::db;
+use crate::model::{
+    CheckedMonitoringTargetStatus, CompositeRuleDescriptor, MonitoringTargetStatus,
+};
+
+/// Computes the status of a composite target from the effective statuses of
+/// its members. Paused, in maintenance and pending members are left out.
+/// Unknown and unreachable members count as unhealthy.
+pub fn evaluate(
+    conn: &Connection,
+    members: &[String],
+    rule: &CompositeRuleDescriptor,
+) -> Result<CheckedMonitoringTargetStatus> {
+    let mut statuses = vec![];
+    for member in members.iter() {
+        let state = db::get_target_state(conn, member)?;
+        let status = match state.effective_status {
+            None
+            | Some(MonitoringTargetStatus::Paused)
+            | Some(MonitoringTargetStatus::Maintenance)
+            | Some(MonitoringTargetStatus::Pending) => continue,
+            Some(MonitoringTargetStatus::Unknown) | Some(MonitoringTargetStatus::Unreachable) => {
+                MonitoringTargetStatus::Unhealthy
+            }
+            Some(status) => status,
+        };
+        statuses.push((member, status));
+    }
+    if statuses.is_empty() {
+        return Ok(CheckedMonitoringTargetStatus {
+            status: MonitoringTargetStatus::Unknown,
+            description: "No member has been checked".to_string(),
+            metrics: HashMap::new(),
+        });
+    }
+    let healthy = statuses
+        .iter()
+        .filter(|(_, status)| *status == MonitoringTargetStatus::Healthy)
+        .count();
+    let status = match rule {
+        CompositeRuleDescriptor::AllHealthy if healthy == statuses.len() => {
+            MonitoringTargetStatus::Healthy
+        }
+        CompositeRuleDescriptor::AtLeast { count } if healthy >= *count => {
+            MonitoringTargetStatus::Healthy
+        }
+        CompositeRuleDescriptor::AllHealthy | CompositeRuleDescriptor::AtLeast { .. } => {
+            MonitoringTargetStatus::Unhealthy
+        }
+        CompositeRuleDescriptor::WorstOf => statuses
+            .iter()
+            .map(|(_, status)| status.clone())
+            .max_by_key(|status| status.severity())
+            .unwrap(),
+        CompositeRuleDescriptor::BestOf => statuses
+            .iter()
+            .map(|(_, status)| status.clone())
+            .min_by_key(|status| status.severity())
+            .unwrap(),
+        CompositeRuleDescriptor::Weighted { weights, threshold } => {
+            let weight = |member: &String| weights.get(member).copied().unwrap_or(1.0);
+            let total: f64 = statuses.iter().map(|(member, _)| weight(member)).sum();
+            let healthy_weight: f64 = statuses
+                .iter()
+                .filter(|(_, status)| *status == MonitoringTargetStatus::Healthy)
+                .map(|(member, _)| weight(member))
+                .sum();
+            if total > 0.0 && healthy_weight / total >= *threshold {
+                MonitoringTargetStatus::Healthy
+            } else if healthy > 0 {
+                MonitoringTargetStatus::Degraded
+            } else {
+                MonitoringTargetStatus::Unhealthy
+            }
+        }
+    };
+    Ok(CheckedMonitoringTargetStatus {
+        status,
+        description: format!("{} of {} members healthy", healthy, statuses.len()),
+        metrics: HashMap::from([
+            ("healthy_members".to_string(), healthy as f64),
+            ("members".to_string(), statuses.len() as f64),
+        ]),
+    })
+}
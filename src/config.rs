@@ -0,0 +1,85 @@
+use rocket::serde::json::{serde_json, Value};
+
+use crate::args::Args;
+use crate::model::{Config, MonitoringTargetDescriptor, MonitoringTargetTypeDescriptor};
+
+/// Targets that a target depends on or, for composite targets, is computed
+/// from.
+fn referenced_targets(target: &MonitoringTargetDescriptor) -> Vec<String> {
+    let mut references = target.depends_on.clone();
+    if let MonitoringTargetTypeDescriptor::Composite { members, .. } = &target.target {
+        references.extend(members.iter().cloned());
+    }
+    references
+}
+
+/// Panics if a target references an unknown target or itself, directly or
+/// through other targets. A cycle of composite targets would re-evaluate
+/// forever.
+fn check_dependencies(targets: &[MonitoringTargetDescriptor]) {
+    for target in targets.iter() {
+        let mut path = vec![target.id.clone()];
+        let mut pending = vec![(target, referenced_targets(target), 0)];
+        // Depth-first search that keeps the current path to detect cycles.
+        while let Some((current, references, index)) = pending.pop() {
+            let Some(dependency_id) = references.get(index).cloned() else {
+                path.pop();
+                continue;
+            };
+            pending.push((current, references, index + 1));
+            let Some(dependency) = targets.iter().find(|target| target.id == dependency_id) else {
+                panic!(
+                    "Unknown target referenced by {}: {}",
+                    current.id, dependency_id
+                );
+            };
+            if path.contains(&dependency_id) {
+                panic!(
+                    "Dependency cycle: {} -> {}",
+                    path.join(" -> "),
+                    dependency_id
+                );
+            }
+            path.push(dependency_id);
+            pending.push((dependency, referenced_targets(dependency), 0));
+        }
+    }
+}
+
+/// Reads the config file. A plain list of targets is still accepted and
+/// treated as a config without notifiers. Escalation policies are checked to
+/// only reference known notifiers and target references to be acyclic.
+pub fn load_config(args: &Args) -> Config {
+    let Some(path) = &args.config else {
+        return Config::default();
+    };
+    let content = std::fs::read_to_string(path).unwrap();
+    let value: Value = serde_json::from_str(&content).unwrap();
+    let mut config = if value.is_array() {
+        Config {
+            targets: serde_json::from_value::<Vec<MonitoringTargetDescriptor>>(value).unwrap(),
+            ..Config::default()
+        }
+    } else {
+        serde_json::from_value::<Config>(value).unwrap()
+    };
+    for policy in config.escalation_policies.iter_mut() {
+        for level in policy.levels.iter() {
+            for notifier_id in level.notifiers.iter() {
+                if !config
+                    .notifiers
+                    .iter()
+                    .any(|notifier| &notifier.id == notifier_id)
+                {
+                    panic!(
+                        "Unknown notifier in escalation policy {}: {}",
+                        policy.id, notifier_id
+                    );
+                }
+            }
+        }
+        policy.levels.sort_by_key(|level| level.after);
+    }
+    check_dependencies(&config.targets);
+    config
+}
@@ -1,13 +1,48 @@
+use std::collections::HashMap;
 use std::path::Path;
 
-use rusqlite::{params, Connection, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Result, Row, TransactionBehavior};
 
-use crate::model::{MonitoringTargetDescriptor, Observation, ObservedMonitoringTargetStatus};
+use crate::calendar::CronExpression;
+use crate::model::{
+    Acknowledgement, CheckCounters, Escalation, Incident, Message, MonitoringTargetDescriptor,
+    MonitoringTargetState, MonitoringTargetStatus, NotificationDelivery, Observation,
+    ObservedMonitoringTargetStatus, RecordedMessage, Silence, Uptime,
+};
 use rocket::serde::json::serde_json;
 
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT COUNT(*) FROM pragma_table_info('{}') WHERE name = ?",
+        table
+    ))?;
+    let count: i64 = stmt.query_row(params![column], |row| row.get(0))?;
+    if count == 0 {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// Opens a connection to the database, which `migrate_db` has set up.
 pub fn init_db(path: &Path) -> Result<Connection> {
-    let conn = Connection::open(path)?;
-    conn.execute(
+    Connection::open(path)
+}
+
+/// Creates and migrates the schema. Runs once at startup, before any other
+/// connection is opened.
+pub fn migrate_db(path: &Path) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    tx.execute(
         "CREATE TABLE IF NOT EXISTS monitoring_targets (
             id TEXT PRIMARY KEY,
             name TEXT NOT NULL,
@@ -18,7 +53,61 @@ pub fn init_db(path: &Path) -> Result<Connection> {
         )",
         [],
     )?;
-    conn.execute(
+    add_column_if_missing(&tx, "monitoring_targets", "target_group", "TEXT")?;
+    add_column_if_missing(&tx, "monitoring_targets", "flap_detection", "TEXT")?;
+    add_column_if_missing(
+        &tx,
+        "monitoring_targets",
+        "failures_before_unhealthy",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+    add_column_if_missing(
+        &tx,
+        "monitoring_targets",
+        "successes_before_recovery",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+    add_column_if_missing(&tx, "monitoring_targets", "retry_interval", "INTEGER")?;
+    add_column_if_missing(
+        &tx,
+        "monitoring_targets",
+        "retry_backoff",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        &tx,
+        "monitoring_targets",
+        "retry_jitter",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(&tx, "monitoring_targets", "schedule", "TEXT")?;
+    add_column_if_missing(&tx, "monitoring_targets", "timezone", "TEXT")?;
+    add_column_if_missing(&tx, "monitoring_targets", "active_hours", "TEXT")?;
+    add_column_if_missing(
+        &tx,
+        "monitoring_targets",
+        "jitter",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        &tx,
+        "monitoring_targets",
+        "paused",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        &tx,
+        "monitoring_targets",
+        "depends_on",
+        "TEXT NOT NULL DEFAULT '[]'",
+    )?;
+    add_column_if_missing(
+        &tx,
+        "monitoring_targets",
+        "tags",
+        "TEXT NOT NULL DEFAULT '[]'",
+    )?;
+    tx.execute(
         "CREATE TABLE IF NOT EXISTS observations (
             monitoring_target_id TEXT,
             timestamp TEXT,
@@ -30,7 +119,125 @@ pub fn init_db(path: &Path) -> Result<Connection> {
         )",
         [],
     )?;
-    Ok(conn)
+    add_column_if_missing(
+        &tx,
+        "observations",
+        "queue_time",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        &tx,
+        "observations",
+        "duration",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(&tx, "observations", "metrics", "TEXT NOT NULL DEFAULT '{}'")?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS incidents (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            monitoring_target_id TEXT NOT NULL,
+            start TEXT NOT NULL,
+            end TEXT,
+            worst_status TEXT NOT NULL,
+            description TEXT NOT NULL,
+            observations INTEGER NOT NULL,
+            FOREIGN KEY (monitoring_target_id) REFERENCES monitoring_targets (id)
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS notification_deliveries (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            notifier_id TEXT NOT NULL,
+            monitoring_target_id TEXT NOT NULL,
+            timestamp TEXT NOT NULL,
+            attempt INTEGER NOT NULL,
+            success INTEGER NOT NULL,
+            error TEXT,
+            FOREIGN KEY (monitoring_target_id) REFERENCES monitoring_targets (id)
+        )",
+        [],
+    )?;
+    add_column_if_missing(&tx, "incidents", "acknowledged_at", "TEXT")?;
+    add_column_if_missing(&tx, "incidents", "acknowledged_by", "TEXT")?;
+    add_column_if_missing(&tx, "incidents", "acknowledgement_comment", "TEXT")?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS silences (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            monitoring_target_id TEXT,
+            target_group TEXT,
+            start TEXT NOT NULL,
+            end TEXT NOT NULL,
+            author TEXT NOT NULL,
+            comment TEXT NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS escalations (
+            incident_id INTEGER NOT NULL,
+            policy_id TEXT NOT NULL,
+            level INTEGER NOT NULL,
+            last_notified TEXT NOT NULL,
+            PRIMARY KEY (incident_id, policy_id),
+            FOREIGN KEY (incident_id) REFERENCES incidents (id)
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS target_states (
+            monitoring_target_id TEXT PRIMARY KEY,
+            last_status TEXT,
+            flapping INTEGER NOT NULL,
+            flapping_since TEXT,
+            transitions TEXT NOT NULL,
+            FOREIGN KEY (monitoring_target_id) REFERENCES monitoring_targets (id)
+        )",
+        [],
+    )?;
+    add_column_if_missing(&tx, "target_states", "effective_status", "TEXT")?;
+    add_column_if_missing(&tx, "target_states", "since", "TEXT")?;
+    add_column_if_missing(
+        &tx,
+        "target_states",
+        "consecutive_failures",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        &tx,
+        "target_states",
+        "consecutive_successes",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS check_counters (
+            monitoring_target_id TEXT PRIMARY KEY,
+            checks INTEGER NOT NULL,
+            failures INTEGER NOT NULL,
+            retries INTEGER NOT NULL,
+            FOREIGN KEY (monitoring_target_id) REFERENCES monitoring_targets (id)
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            monitoring_target_id TEXT,
+            transition INTEGER NOT NULL,
+            message TEXT NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS exporter_cursors (
+            exporter_id TEXT PRIMARY KEY,
+            last_event_id INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    tx.commit()?;
+    Ok(())
 }
 
 pub fn delete_old_observations(conn: &Connection, keep_days: u32) -> Result<()> {
@@ -42,10 +249,30 @@ pub fn delete_old_observations(conn: &Connection, keep_days: u32) -> Result<()>
     Ok(())
 }
 
+pub fn delete_old_notification_deliveries(conn: &Connection, keep_days: u32) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "DELETE FROM notification_deliveries
+        WHERE timestamp < datetime('now', ?)",
+    )?;
+    stmt.execute(params![format!("-{} days", keep_days)])?;
+    Ok(())
+}
+
+pub fn delete_old_events(conn: &Connection, keep_days: u32) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "DELETE FROM events
+        WHERE timestamp < datetime('now', ?)",
+    )?;
+    stmt.execute(params![format!("-{} days", keep_days)])?;
+    Ok(())
+}
+
 pub fn add_observation(conn: &Connection, observation: &Observation) -> Result<()> {
     let mut insert_observation = conn.prepare(
-        "INSERT INTO observations (monitoring_target_id, timestamp, status, description, retries)
-        VALUES (?, ?, ?, ?, ?)",
+        "INSERT INTO observations
+            (monitoring_target_id, timestamp, status, description, retries, queue_time,
+            duration, metrics)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
     )?;
     let observed_status = &observation.observed_status;
     insert_observation.execute((
@@ -54,26 +281,69 @@ pub fn add_observation(conn: &Connection, observation: &Observation) -> Result<(
         serde_json::to_string(&observed_status.status).unwrap(),
         &observed_status.description,
         observed_status.retries,
+        observed_status.queue_time,
+        observed_status.duration,
+        serde_json::to_string(&observed_status.metrics).unwrap(),
     ))?;
     Ok(())
 }
 
+/// Maps the observation columns starting at `start`, in the order of
+/// `get_last_observations`.
+fn observed_status_from_row(row: &Row, start: usize) -> Result<ObservedMonitoringTargetStatus> {
+    Ok(ObservedMonitoringTargetStatus {
+        timestamp: row.get(start)?,
+        status: serde_json::from_str(&row.get::<_, String>(start + 1)?).unwrap(),
+        description: row.get(start + 2)?,
+        retries: row.get(start + 3)?,
+        queue_time: row.get(start + 4)?,
+        duration: row.get(start + 5)?,
+        metrics: serde_json::from_str(&row.get::<_, String>(start + 6)?).unwrap(),
+    })
+}
+
+fn monitoring_target_from_row(row: &Row) -> Result<MonitoringTargetDescriptor> {
+    Ok(MonitoringTargetDescriptor {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        interval: row.get(2)?,
+        retries: row.get(3)?,
+        timeout: row.get(4)?,
+        target: serde_json::from_str(&row.get::<_, String>(5)?).unwrap(),
+        group: row.get(6)?,
+        flap_detection: row
+            .get::<_, Option<String>>(7)?
+            .map(|text| serde_json::from_str(&text).unwrap()),
+        failures_before_unhealthy: row.get(8)?,
+        successes_before_recovery: row.get(9)?,
+        retry_interval: row.get(10)?,
+        retry_backoff: row.get(11)?,
+        retry_jitter: row.get(12)?,
+        schedule: row
+            .get::<_, Option<String>>(13)?
+            .map(|text| CronExpression::parse(&text).unwrap()),
+        timezone: row
+            .get::<_, Option<String>>(14)?
+            .map(|text| text.parse().unwrap()),
+        active_hours: row
+            .get::<_, Option<String>>(15)?
+            .map(|text| serde_json::from_str(&text).unwrap()),
+        jitter: row.get(16)?,
+        depends_on: serde_json::from_str(&row.get::<_, String>(17)?).unwrap(),
+        tags: serde_json::from_str(&row.get::<_, String>(18)?).unwrap(),
+    })
+}
+
 pub fn get_monitoring_target_descriptors(
     conn: &Connection,
 ) -> Result<Vec<MonitoringTargetDescriptor>> {
-    let mut stmt = conn
-        .prepare("SELECT id, name, interval, retries, timeout, target FROM monitoring_targets")?;
-    let monitoring_targets_iter = stmt.query_map([], |row| {
-        let target_text: String = row.get(5)?;
-        Ok(MonitoringTargetDescriptor {
-            id: row.get(0)?,
-            name: row.get(1)?,
-            interval: row.get(2)?,
-            retries: row.get(3)?,
-            timeout: row.get(4)?,
-            target: serde_json::from_str(&target_text).unwrap(),
-        })
-    })?;
+    let mut stmt = conn.prepare(
+        "SELECT id, name, interval, retries, timeout, target, target_group, flap_detection,
+            failures_before_unhealthy, successes_before_recovery, retry_interval, retry_backoff,
+            retry_jitter, schedule, timezone, active_hours, jitter, depends_on, tags
+        FROM monitoring_targets",
+    )?;
+    let monitoring_targets_iter = stmt.query_map([], monitoring_target_from_row)?;
     monitoring_targets_iter.collect::<Result<Vec<MonitoringTargetDescriptor>>>()
 }
 
@@ -83,26 +353,33 @@ pub fn has_monitoring_target(conn: &Connection, id: &str) -> Result<bool> {
     Ok(count > 0)
 }
 
+pub fn is_paused(conn: &Connection, id: &str) -> Result<bool> {
+    let mut stmt = conn.prepare("SELECT paused FROM monitoring_targets WHERE id = ?")?;
+    let paused = stmt.query_row(params![id], |row| row.get(0)).optional()?;
+    Ok(paused.unwrap_or(false))
+}
+
+pub fn set_paused(conn: &Connection, id: &str, paused: bool) -> Result<()> {
+    let mut stmt = conn.prepare("UPDATE monitoring_targets SET paused = ? WHERE id = ?")?;
+    stmt.execute(params![paused, id])?;
+    Ok(())
+}
+
 pub fn get_last_observations(
     conn: &Connection,
-    ids: &Vec<String>,
+    ids: &[String],
 ) -> Result<Vec<ObservedMonitoringTargetStatus>> {
     let mut result = vec![];
     for id in ids.iter() {
         let mut stmt = conn.prepare(
-            "SELECT timestamp, status, description, retries FROM observations
+            "SELECT timestamp, status, description, retries, queue_time, duration, metrics
+            FROM observations
             WHERE monitoring_target_id = ?
             ORDER BY timestamp DESC
             LIMIT 1",
         )?;
-        let observation_iter = stmt.query_map(params![id], |row| {
-            Ok(ObservedMonitoringTargetStatus {
-                timestamp: row.get(0)?,
-                status: serde_json::from_str(&row.get::<_, String>(1)?).unwrap(),
-                description: row.get(2)?,
-                retries: row.get(3)?,
-            })
-        })?;
+        let observation_iter =
+            stmt.query_map(params![id], |row| observed_status_from_row(row, 0))?;
         for observation in observation_iter {
             result.push(observation.unwrap());
         }
@@ -112,39 +389,27 @@ pub fn get_last_observations(
 
 pub fn get_monitoring_target(conn: &Connection, id: &str) -> Result<MonitoringTargetDescriptor> {
     let mut stmt = conn.prepare(
-        "SELECT name, interval, retries, timeout, target FROM monitoring_targets
+        "SELECT id, name, interval, retries, timeout, target, target_group, flap_detection,
+            failures_before_unhealthy, successes_before_recovery, retry_interval, retry_backoff,
+            retry_jitter, schedule, timezone, active_hours, jitter, depends_on, tags
+        FROM monitoring_targets
         WHERE id = ?",
     )?;
-    let mut monitoring_target_iter = stmt.query_map(params![id], |row| {
-        Ok(MonitoringTargetDescriptor {
-            id: id.to_string(),
-            name: row.get(0)?,
-            interval: row.get(1)?,
-            retries: row.get(2)?,
-            timeout: row.get(3)?,
-            target: serde_json::from_str(&row.get::<_, String>(4)?).unwrap(),
-        })
-    })?;
-    let monitoring_target = monitoring_target_iter.next().unwrap();
-    monitoring_target
+    stmt.query_row(params![id], monitoring_target_from_row)
 }
 
 pub fn get_observations(conn: &Connection, id: &str) -> Result<Vec<Observation>> {
     let monitoring_target = get_monitoring_target(conn, id)?;
     let mut stmt = conn.prepare(
-        "SELECT timestamp, status, description, retries FROM observations
+        "SELECT timestamp, status, description, retries, queue_time, duration, metrics
+            FROM observations
         WHERE monitoring_target_id = ?
         ORDER BY timestamp DESC",
     )?;
     let observation_iter = stmt.query_map(params![id], |row| {
         Ok(Observation {
             monitoring_target: monitoring_target.clone(),
-            observed_status: ObservedMonitoringTargetStatus {
-                timestamp: row.get(0)?,
-                status: serde_json::from_str(&row.get::<_, String>(1)?).unwrap(),
-                description: row.get(2)?,
-                retries: row.get(3)?,
-            },
+            observed_status: observed_status_from_row(row, 0)?,
         })
     })?;
     observation_iter.collect::<Result<Vec<Observation>>>()
@@ -155,17 +420,610 @@ pub fn create_or_update_monitoring_target(
     monitoring_target: &MonitoringTargetDescriptor,
 ) -> Result<()> {
     let mut stmt = conn.prepare(
-        "INSERT OR REPLACE INTO monitoring_targets (id, name, interval, retries, timeout, target)
-        VALUES (?, ?, ?, ?, ?, ?)",
+        "INSERT OR REPLACE INTO monitoring_targets
+            (id, name, interval, retries, timeout, target, target_group, flap_detection,
+            failures_before_unhealthy, successes_before_recovery, retry_interval, retry_backoff,
+            retry_jitter, schedule, timezone, active_hours, jitter, depends_on, tags, paused)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?,
+            COALESCE((SELECT paused FROM monitoring_targets WHERE id = ?), 0))",
     )?;
     let target_text = serde_json::to_string(&monitoring_target.target).unwrap();
-    stmt.execute((
+    let flap_detection_text = monitoring_target
+        .flap_detection
+        .as_ref()
+        .map(|flap_detection| serde_json::to_string(flap_detection).unwrap());
+    let active_hours_text = monitoring_target
+        .active_hours
+        .as_ref()
+        .map(|active_hours| serde_json::to_string(active_hours).unwrap());
+    stmt.execute(params![
         &monitoring_target.id,
         &monitoring_target.name,
         monitoring_target.interval,
         monitoring_target.retries,
         monitoring_target.timeout,
         target_text,
+        &monitoring_target.group,
+        flap_detection_text,
+        monitoring_target.failures_before_unhealthy,
+        monitoring_target.successes_before_recovery,
+        monitoring_target.retry_interval,
+        monitoring_target.retry_backoff,
+        monitoring_target.retry_jitter,
+        monitoring_target
+            .schedule
+            .as_ref()
+            .map(|schedule| schedule.to_string()),
+        monitoring_target.timezone.map(|timezone| timezone.name()),
+        active_hours_text,
+        monitoring_target.jitter,
+        serde_json::to_string(&monitoring_target.depends_on).unwrap(),
+        serde_json::to_string(&monitoring_target.tags).unwrap(),
+        // Pausing is not part of the config and survives restarts.
+        &monitoring_target.id,
+    ])?;
+    Ok(())
+}
+
+fn incident_from_row(row: &Row) -> Result<Incident> {
+    Ok(Incident {
+        id: row.get(0)?,
+        monitoring_target_id: row.get(1)?,
+        start: row.get(2)?,
+        end: row.get(3)?,
+        worst_status: serde_json::from_str(&row.get::<_, String>(4)?).unwrap(),
+        description: row.get(5)?,
+        observations: row.get(6)?,
+        acknowledgement: match row.get(7)? {
+            Some(timestamp) => Some(Acknowledgement {
+                timestamp,
+                author: row.get(8)?,
+                comment: row.get(9)?,
+            }),
+            None => None,
+        },
+        silences: vec![],
+    })
+}
+
+pub fn get_open_incident(conn: &Connection, id: &str) -> Result<Option<Incident>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, monitoring_target_id, start, end, worst_status, description, observations,
+            acknowledged_at, acknowledged_by, acknowledgement_comment
+        FROM incidents
+        WHERE monitoring_target_id = ? AND end IS NULL
+        ORDER BY start DESC
+        LIMIT 1",
+    )?;
+    stmt.query_row(params![id], incident_from_row).optional()
+}
+
+pub fn get_last_incident(conn: &Connection, id: &str) -> Result<Option<Incident>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, monitoring_target_id, start, end, worst_status, description, observations,
+            acknowledged_at, acknowledged_by, acknowledgement_comment
+        FROM incidents
+        WHERE monitoring_target_id = ?
+        ORDER BY start DESC
+        LIMIT 1",
+    )?;
+    stmt.query_row(params![id], incident_from_row).optional()
+}
+
+pub fn get_incidents(
+    conn: &Connection,
+    id: Option<&str>,
+    open_only: bool,
+) -> Result<Vec<Incident>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, monitoring_target_id, start, end, worst_status, description, observations,
+            acknowledged_at, acknowledged_by, acknowledgement_comment
+        FROM incidents
+        WHERE (?1 IS NULL OR monitoring_target_id = ?1) AND (?2 = 0 OR end IS NULL)
+        ORDER BY start DESC",
+    )?;
+    let incident_iter = stmt.query_map(params![id, open_only], incident_from_row)?;
+    let mut incidents = incident_iter.collect::<Result<Vec<Incident>>>()?;
+    for incident in incidents.iter_mut() {
+        incident.silences = get_incident_silences(conn, incident)?;
+    }
+    Ok(incidents)
+}
+
+pub fn open_incident(conn: &Connection, observation: &Observation) -> Result<Incident> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO incidents (monitoring_target_id, start, end, worst_status, description, observations)
+        VALUES (?, ?, NULL, ?, ?, 1)",
+    )?;
+    let observed_status = &observation.observed_status;
+    stmt.execute((
+        &observation.monitoring_target.id,
+        observed_status.timestamp.to_rfc3339(),
+        serde_json::to_string(&observed_status.status).unwrap(),
+        &observed_status.description,
+    ))?;
+    Ok(Incident {
+        id: conn.last_insert_rowid(),
+        monitoring_target_id: observation.monitoring_target.id.clone(),
+        start: observed_status.timestamp,
+        end: None,
+        worst_status: observed_status.status.clone(),
+        description: observed_status.description.clone(),
+        observations: 1,
+        acknowledgement: None,
+        silences: vec![],
+    })
+}
+
+pub fn acknowledge_incident(
+    conn: &Connection,
+    incident: &Incident,
+    acknowledgement: &Acknowledgement,
+) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "UPDATE incidents SET acknowledged_at = ?, acknowledged_by = ?, acknowledgement_comment = ?
+        WHERE id = ?",
+    )?;
+    stmt.execute((
+        acknowledgement.timestamp.to_rfc3339(),
+        &acknowledgement.author,
+        &acknowledgement.comment,
+        incident.id,
     ))?;
     Ok(())
 }
+
+pub fn update_incident(conn: &Connection, incident: &Incident) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "UPDATE incidents SET end = ?, worst_status = ?, observations = ?
+        WHERE id = ?",
+    )?;
+    stmt.execute((
+        incident.end.map(|end| end.to_rfc3339()),
+        serde_json::to_string(&incident.worst_status).unwrap(),
+        incident.observations,
+        incident.id,
+    ))?;
+    Ok(())
+}
+
+pub fn add_notification_delivery(conn: &Connection, delivery: &NotificationDelivery) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO notification_deliveries (notifier_id, monitoring_target_id, timestamp, attempt, success, error)
+        VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    stmt.execute((
+        &delivery.notifier_id,
+        &delivery.monitoring_target_id,
+        delivery.timestamp.to_rfc3339(),
+        delivery.attempt,
+        delivery.success,
+        &delivery.error,
+    ))?;
+    Ok(())
+}
+
+pub fn get_notification_deliveries(
+    conn: &Connection,
+    notifier_id: Option<&str>,
+) -> Result<Vec<NotificationDelivery>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, notifier_id, monitoring_target_id, timestamp, attempt, success, error
+        FROM notification_deliveries
+        WHERE ?1 IS NULL OR notifier_id = ?1
+        ORDER BY timestamp DESC",
+    )?;
+    let delivery_iter = stmt.query_map(params![notifier_id], |row| {
+        Ok(NotificationDelivery {
+            id: row.get(0)?,
+            notifier_id: row.get(1)?,
+            monitoring_target_id: row.get(2)?,
+            timestamp: row.get(3)?,
+            attempt: row.get(4)?,
+            success: row.get(5)?,
+            error: row.get(6)?,
+        })
+    })?;
+    delivery_iter.collect::<Result<Vec<NotificationDelivery>>>()
+}
+
+pub fn get_incident(conn: &Connection, id: i64) -> Result<Option<Incident>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, monitoring_target_id, start, end, worst_status, description, observations,
+            acknowledged_at, acknowledged_by, acknowledgement_comment
+        FROM incidents
+        WHERE id = ?",
+    )?;
+    stmt.query_row(params![id], incident_from_row).optional()
+}
+
+pub fn get_escalations(conn: &Connection) -> Result<Vec<Escalation>> {
+    let mut stmt =
+        conn.prepare("SELECT incident_id, policy_id, level, last_notified FROM escalations")?;
+    let escalation_iter = stmt.query_map([], |row| {
+        Ok(Escalation {
+            incident_id: row.get(0)?,
+            policy_id: row.get(1)?,
+            level: row.get(2)?,
+            last_notified: row.get(3)?,
+        })
+    })?;
+    escalation_iter.collect::<Result<Vec<Escalation>>>()
+}
+
+pub fn set_escalation(conn: &Connection, escalation: &Escalation) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT OR REPLACE INTO escalations (incident_id, policy_id, level, last_notified)
+        VALUES (?, ?, ?, ?)",
+    )?;
+    stmt.execute((
+        escalation.incident_id,
+        &escalation.policy_id,
+        escalation.level,
+        escalation.last_notified.to_rfc3339(),
+    ))?;
+    Ok(())
+}
+
+pub fn delete_escalation(conn: &Connection, escalation: &Escalation) -> Result<()> {
+    let mut stmt =
+        conn.prepare("DELETE FROM escalations WHERE incident_id = ? AND policy_id = ?")?;
+    stmt.execute((escalation.incident_id, &escalation.policy_id))?;
+    Ok(())
+}
+
+fn silence_from_row(row: &Row) -> Result<Silence> {
+    Ok(Silence {
+        id: row.get(0)?,
+        monitoring_target_id: row.get(1)?,
+        group: row.get(2)?,
+        start: row.get(3)?,
+        end: row.get(4)?,
+        author: row.get(5)?,
+        comment: row.get(6)?,
+    })
+}
+
+pub fn add_silence(conn: &Connection, silence: &Silence) -> Result<Silence> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO silences (monitoring_target_id, target_group, start, end, author, comment)
+        VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    let start = silence.start.unwrap_or_else(Utc::now);
+    stmt.execute((
+        &silence.monitoring_target_id,
+        &silence.group,
+        start.to_rfc3339(),
+        silence.end.to_rfc3339(),
+        &silence.author,
+        &silence.comment,
+    ))?;
+    Ok(Silence {
+        id: conn.last_insert_rowid(),
+        start: Some(start),
+        ..silence.clone()
+    })
+}
+
+pub fn get_silence(conn: &Connection, id: i64) -> Result<Option<Silence>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, monitoring_target_id, target_group, start, end, author, comment FROM silences
+        WHERE id = ?",
+    )?;
+    stmt.query_row(params![id], silence_from_row).optional()
+}
+
+/// Returns all silences, or only those active at `at`.
+pub fn get_silences(conn: &Connection, at: Option<DateTime<Utc>>) -> Result<Vec<Silence>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, monitoring_target_id, target_group, start, end, author, comment FROM silences
+        WHERE ?1 IS NULL OR (start <= ?1 AND end > ?1)
+        ORDER BY start DESC",
+    )?;
+    let silence_iter = stmt.query_map(params![at.map(|at| at.to_rfc3339())], silence_from_row)?;
+    silence_iter.collect::<Result<Vec<Silence>>>()
+}
+
+pub fn is_silenced(conn: &Connection, target: &MonitoringTargetDescriptor) -> Result<bool> {
+    let silences = get_silences(conn, Some(Utc::now()))?;
+    Ok(silences.iter().any(|silence| silence.applies_to(target)))
+}
+
+fn get_incident_silences(conn: &Connection, incident: &Incident) -> Result<Vec<Silence>> {
+    let mut stmt = conn.prepare(
+        "SELECT s.id, s.monitoring_target_id, s.target_group, s.start, s.end, s.author, s.comment
+        FROM silences s, monitoring_targets t
+        WHERE t.id = ?1 AND s.start <= ?2 AND s.end >= ?3
+            AND (s.monitoring_target_id = t.id
+                OR t.target_group = s.target_group
+                OR t.target_group LIKE s.target_group || '/%')
+        ORDER BY s.start",
+    )?;
+    let end = incident.end.unwrap_or_else(Utc::now);
+    let silence_iter = stmt.query_map(
+        params![
+            incident.monitoring_target_id,
+            end.to_rfc3339(),
+            incident.start.to_rfc3339()
+        ],
+        silence_from_row,
+    )?;
+    silence_iter.collect::<Result<Vec<Silence>>>()
+}
+
+/// Ends the silence now unless it already ended.
+pub fn end_silence(conn: &Connection, id: i64) -> Result<()> {
+    let mut stmt = conn.prepare("UPDATE silences SET end = ?1 WHERE id = ?2 AND end > ?1")?;
+    stmt.execute(params![Utc::now().to_rfc3339(), id])?;
+    Ok(())
+}
+
+pub fn delete_old_silences(conn: &Connection, keep_days: u32) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "DELETE FROM silences
+        WHERE end < datetime('now', ?)",
+    )?;
+    stmt.execute(params![format!("-{} days", keep_days)])?;
+    Ok(())
+}
+
+pub fn get_uptime(conn: &Connection, id: &str, since: DateTime<Utc>) -> Result<Uptime> {
+    let mut stmt = conn.prepare(
+        "SELECT status, COUNT(*) FROM observations
+        WHERE monitoring_target_id = ? AND timestamp >= ?
+        GROUP BY status",
+    )?;
+    let count_iter = stmt.query_map(params![id, since.to_rfc3339()], |row| {
+        let status: MonitoringTargetStatus =
+            serde_json::from_str(&row.get::<_, String>(0)?).unwrap();
+        Ok((status, row.get::<_, u32>(1)?))
+    })?;
+    let mut observations = 0;
+    let mut healthy = 0;
+    for count in count_iter {
+        let (status, count) = count?;
+        if !status.counts_for_uptime() {
+            continue;
+        }
+        observations += count;
+        if status == MonitoringTargetStatus::Healthy {
+            healthy += count;
+        }
+    }
+    Ok(Uptime {
+        monitoring_target_id: id.to_string(),
+        since,
+        observations,
+        healthy,
+        uptime: (observations > 0).then(|| healthy as f64 / observations as f64),
+    })
+}
+
+/// Maps the state columns starting at `start`, in the order of
+/// `get_target_state`.
+fn target_state_from_row(row: &Row, start: usize) -> Result<MonitoringTargetState> {
+    Ok(MonitoringTargetState {
+        effective_status: row
+            .get::<_, Option<String>>(start)?
+            .map(|text| serde_json::from_str(&text).unwrap()),
+        since: row.get(start + 1)?,
+        consecutive_failures: row.get(start + 2)?,
+        consecutive_successes: row.get(start + 3)?,
+        last_status: row
+            .get::<_, Option<String>>(start + 4)?
+            .map(|text| serde_json::from_str(&text).unwrap()),
+        flapping: row.get(start + 5)?,
+        flapping_since: row.get(start + 6)?,
+        transitions: serde_json::from_str(&row.get::<_, String>(start + 7)?).unwrap(),
+    })
+}
+
+pub fn get_target_state(conn: &Connection, id: &str) -> Result<MonitoringTargetState> {
+    let mut stmt = conn.prepare(
+        "SELECT effective_status, since, consecutive_failures, consecutive_successes,
+            last_status, flapping, flapping_since, transitions
+        FROM target_states
+        WHERE monitoring_target_id = ?",
+    )?;
+    let state = stmt
+        .query_row(params![id], |row| target_state_from_row(row, 0))
+        .optional()?;
+    Ok(state.unwrap_or_default())
+}
+
+/// Loads every target, or only the target with `id`, together with its last
+/// observation, if it has been checked, and its state in a single query.
+pub fn get_target_statuses(
+    conn: &Connection,
+    id: Option<&str>,
+) -> Result<
+    Vec<(
+        MonitoringTargetDescriptor,
+        Option<ObservedMonitoringTargetStatus>,
+        MonitoringTargetState,
+    )>,
+> {
+    let mut stmt = conn.prepare(
+        "SELECT t.id, t.name, t.interval, t.retries, t.timeout, t.target, t.target_group,
+            t.flap_detection, t.failures_before_unhealthy, t.successes_before_recovery,
+            t.retry_interval, t.retry_backoff, t.retry_jitter, t.schedule, t.timezone,
+            t.active_hours, t.jitter, t.depends_on, t.tags,
+            o.timestamp, o.status, o.description, o.retries, o.queue_time, o.duration,
+            o.metrics,
+            s.monitoring_target_id, s.effective_status, s.since, s.consecutive_failures,
+            s.consecutive_successes, s.last_status, s.flapping, s.flapping_since, s.transitions
+        FROM monitoring_targets t
+        LEFT JOIN observations o ON o.monitoring_target_id = t.id
+            AND o.timestamp = (
+                SELECT MAX(timestamp) FROM observations WHERE monitoring_target_id = t.id
+            )
+        LEFT JOIN target_states s ON s.monitoring_target_id = t.id
+        WHERE ?1 IS NULL OR t.id = ?1",
+    )?;
+    let status_iter = stmt.query_map(params![id], |row| {
+        let monitoring_target = monitoring_target_from_row(row)?;
+        let observed_status = match row.get::<_, Option<DateTime<Utc>>>(19)? {
+            Some(_) => Some(observed_status_from_row(row, 19)?),
+            None => None,
+        };
+        let state = match row.get::<_, Option<String>>(26)? {
+            Some(_) => target_state_from_row(row, 27)?,
+            None => MonitoringTargetState::default(),
+        };
+        Ok((monitoring_target, observed_status, state))
+    })?;
+    status_iter.collect()
+}
+
+pub fn set_target_state(conn: &Connection, id: &str, state: &MonitoringTargetState) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT OR REPLACE INTO target_states
+            (monitoring_target_id, effective_status, since, consecutive_failures,
+            consecutive_successes, last_status, flapping, flapping_since, transitions)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    stmt.execute((
+        id,
+        state
+            .effective_status
+            .as_ref()
+            .map(|status| serde_json::to_string(status).unwrap()),
+        state.since.map(|since| since.to_rfc3339()),
+        state.consecutive_failures,
+        state.consecutive_successes,
+        state
+            .last_status
+            .as_ref()
+            .map(|status| serde_json::to_string(status).unwrap()),
+        state.flapping,
+        state.flapping_since.map(|since| since.to_rfc3339()),
+        serde_json::to_string(&state.transitions).unwrap(),
+    ))?;
+    Ok(())
+}
+
+fn recorded_message_from_row(row: &Row) -> Result<RecordedMessage> {
+    Ok(RecordedMessage {
+        id: row.get(0)?,
+        timestamp: row.get(1)?,
+        transition: row.get(2)?,
+        message: serde_json::from_str(&row.get::<_, String>(3)?).unwrap(),
+    })
+}
+
+pub fn add_event(
+    conn: &Connection,
+    message: &Message,
+    transition: bool,
+) -> Result<RecordedMessage> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO events (timestamp, monitoring_target_id, transition, message)
+        VALUES (?, ?, ?, ?)",
+    )?;
+    let timestamp = Utc::now();
+    stmt.execute((
+        timestamp.to_rfc3339(),
+        message.monitoring_target_id(),
+        transition,
+        serde_json::to_string(message).unwrap(),
+    ))?;
+    Ok(RecordedMessage {
+        id: conn.last_insert_rowid(),
+        timestamp,
+        transition,
+        message: message.clone(),
+    })
+}
+
+/// Returns the events recorded after the event with id `after`, oldest first.
+pub fn get_events_after(conn: &Connection, after: i64) -> Result<Vec<RecordedMessage>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, timestamp, transition, message FROM events
+        WHERE id > ?
+        ORDER BY id",
+    )?;
+    let event_iter = stmt.query_map(params![after], recorded_message_from_row)?;
+    event_iter.collect::<Result<Vec<RecordedMessage>>>()
+}
+
+/// Returns up to `limit` events recorded after the event with id `after`,
+/// oldest first.
+pub fn get_event_batch(
+    conn: &Connection,
+    after: i64,
+    limit: usize,
+) -> Result<Vec<RecordedMessage>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, timestamp, transition, message FROM events
+        WHERE id > ?
+        ORDER BY id
+        LIMIT ?",
+    )?;
+    let event_iter = stmt.query_map(params![after, limit], recorded_message_from_row)?;
+    event_iter.collect::<Result<Vec<RecordedMessage>>>()
+}
+
+/// Returns the id of the last recorded event, 0 if there is none.
+pub fn get_last_event_id(conn: &Connection) -> Result<i64> {
+    conn.query_row("SELECT COALESCE(MAX(id), 0) FROM events", [], |row| {
+        row.get(0)
+    })
+}
+
+/// Returns the id of the last event the exporter pushed, if it pushed any.
+pub fn get_exporter_cursor(conn: &Connection, exporter_id: &str) -> Result<Option<i64>> {
+    let mut stmt =
+        conn.prepare("SELECT last_event_id FROM exporter_cursors WHERE exporter_id = ?")?;
+    stmt.query_row(params![exporter_id], |row| row.get(0))
+        .optional()
+}
+
+pub fn set_exporter_cursor(conn: &Connection, exporter_id: &str, last_event_id: i64) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT OR REPLACE INTO exporter_cursors (exporter_id, last_event_id) VALUES (?, ?)",
+    )?;
+    stmt.execute(params![exporter_id, last_event_id])?;
+    Ok(())
+}
+
+/// Returns the last status transition of every target.
+pub fn get_last_transitions(conn: &Connection) -> Result<Vec<RecordedMessage>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, timestamp, transition, message FROM events
+        WHERE id IN (
+            SELECT MAX(id) FROM events WHERE transition = 1 GROUP BY monitoring_target_id
+        )",
+    )?;
+    let event_iter = stmt.query_map([], recorded_message_from_row)?;
+    event_iter.collect::<Result<Vec<RecordedMessage>>>()
+}
+
+/// Counts a finished check of the target, including its retries.
+pub fn count_check(conn: &Connection, id: &str, failed: bool, retries: u8) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO check_counters (monitoring_target_id, checks, failures, retries)
+        VALUES (?1, 1, ?2, ?3)
+        ON CONFLICT (monitoring_target_id) DO UPDATE SET
+            checks = checks + 1,
+            failures = failures + ?2,
+            retries = retries + ?3",
+    )?;
+    stmt.execute(params![id, failed as u64, retries])?;
+    Ok(())
+}
+
+pub fn get_check_counters(conn: &Connection) -> Result<HashMap<String, CheckCounters>> {
+    let mut stmt =
+        conn.prepare("SELECT monitoring_target_id, checks, failures, retries FROM check_counters")?;
+    let counter_iter = stmt.query_map([], |row| {
+        Ok((
+            row.get(0)?,
+            CheckCounters {
+                checks: row.get(1)?,
+                failures: row.get(2)?,
+                retries: row.get(3)?,
+            },
+        ))
+    })?;
+    counter_iter.collect()
+}
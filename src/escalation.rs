@@ -0,0 +1,215 @@
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::Utc;
+use rocket::tokio;
+use rusqlite::Connection;
+
+use crate::model::{
+    Config, Escalation, EscalationLevelDescriptor, Incident, MonitoringTargetStatus, Notification,
+    NotificationKind,
+};
+use crate::{args, db, maintenance, notify};
+
+const ESCALATION_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+fn build_notification(
+    conn: &Connection,
+    config: &Config,
+    incident: &Incident,
+    kind: NotificationKind,
+    dashboard_url: &Option<String>,
+) -> Option<Notification> {
+    let monitoring_target = config
+        .targets
+        .iter()
+        .find(|target| target.id == incident.monitoring_target_id)?
+        .clone();
+    let mut observed_status =
+        db::get_last_observations(conn, std::slice::from_ref(&monitoring_target.id))
+            .unwrap()
+            .into_iter()
+            .next()?;
+    let state = db::get_target_state(conn, &monitoring_target.id).unwrap();
+    if let Some(effective_status) = state.effective_status {
+        observed_status.status = effective_status;
+    }
+    Some(Notification {
+        kind,
+        dashboard_url: notify::dashboard_url(dashboard_url, &monitoring_target.id),
+        monitoring_target,
+        previous_status: None,
+        observed_status,
+        incident: Some(incident.clone()),
+    })
+}
+
+fn notify_levels(
+    db_path: &Path,
+    config: &Config,
+    levels: &[EscalationLevelDescriptor],
+    notification: Notification,
+) {
+    for level in levels.iter() {
+        for notifier in config
+            .notifiers
+            .iter()
+            .filter(|notifier| level.notifiers.contains(&notifier.id))
+        {
+            tokio::task::spawn(notify::deliver(
+                db_path.to_path_buf(),
+                notifier.clone(),
+                notification.clone(),
+            ));
+        }
+    }
+}
+
+/// Sends a recovery notice to every level that was notified about a now
+/// closed incident and forgets the escalation. The notice is held back while
+/// the target is flapping and sent once it stopped.
+fn resolve_escalations(
+    conn: &Connection,
+    db_path: &Path,
+    config: &Config,
+    dashboard_url: &Option<String>,
+) {
+    for escalation in db::get_escalations(conn).unwrap() {
+        let incident = db::get_incident(conn, escalation.incident_id).unwrap();
+        if incident
+            .as_ref()
+            .is_some_and(|incident| incident.end.is_none())
+        {
+            continue;
+        }
+        let policy = config
+            .escalation_policies
+            .iter()
+            .find(|policy| policy.id == escalation.policy_id);
+        if let (Some(incident), Some(policy)) = (incident, policy) {
+            if db::get_target_state(conn, &incident.monitoring_target_id)
+                .unwrap()
+                .flapping
+            {
+                continue;
+            }
+            let notification = build_notification(
+                conn,
+                config,
+                &incident,
+                NotificationKind::Resolved,
+                dashboard_url,
+            );
+            if let Some(notification) = notification {
+                let levels = &policy.levels[..(escalation.level as usize).min(policy.levels.len())];
+                notify_levels(db_path, config, levels, notification);
+            }
+        }
+        db::delete_escalation(conn, &escalation).unwrap();
+    }
+}
+
+/// Notifies levels that became due for open incidents and repeats the
+/// notification of all reached levels every `repeat_interval`. Acknowledged
+/// incidents and paused, unreachable, flapping, silenced or in maintenance
+/// targets are not escalated further.
+fn escalate_incidents(
+    conn: &Connection,
+    db_path: &Path,
+    config: &Config,
+    dashboard_url: &Option<String>,
+) {
+    let now = Utc::now();
+    let escalations = db::get_escalations(conn).unwrap();
+    for incident in db::get_incidents(conn, None, true).unwrap() {
+        let Some(target) = config
+            .targets
+            .iter()
+            .find(|target| target.id == incident.monitoring_target_id)
+        else {
+            continue;
+        };
+        let state = db::get_target_state(conn, &target.id).unwrap();
+        if incident.acknowledgement.is_some()
+            || state.effective_status == Some(MonitoringTargetStatus::Unreachable)
+            || state.flapping
+            || db::is_paused(conn, &target.id).unwrap()
+            || db::is_silenced(conn, target).unwrap()
+            || maintenance::in_maintenance(&config.maintenance_windows, target, now)
+        {
+            continue;
+        }
+        for policy in config
+            .escalation_policies
+            .iter()
+            .filter(|policy| policy.is_attached_to(target))
+        {
+            let escalation = escalations
+                .iter()
+                .find(|escalation| {
+                    escalation.incident_id == incident.id && escalation.policy_id == policy.id
+                })
+                .cloned()
+                .unwrap_or(Escalation {
+                    incident_id: incident.id,
+                    policy_id: policy.id.clone(),
+                    level: 0,
+                    last_notified: incident.start,
+                });
+            let elapsed = now - incident.start;
+            let reached = policy
+                .levels
+                .iter()
+                .filter(|level| elapsed >= chrono::Duration::minutes(level.after as i64))
+                .count() as u32;
+            let repeat_due = policy.repeat_interval.is_some_and(|repeat_interval| {
+                now - escalation.last_notified >= chrono::Duration::minutes(repeat_interval as i64)
+            });
+            let (kind, levels) = if reached > escalation.level {
+                (
+                    NotificationKind::Escalation { level: reached },
+                    &policy.levels[escalation.level as usize..reached as usize],
+                )
+            } else if reached > 0 && repeat_due {
+                (
+                    NotificationKind::Reminder,
+                    &policy.levels[..reached as usize],
+                )
+            } else {
+                continue;
+            };
+            if let Some(notification) =
+                build_notification(conn, config, &incident, kind, dashboard_url)
+            {
+                notify_levels(db_path, config, levels, notification);
+            }
+            db::set_escalation(
+                conn,
+                &Escalation {
+                    level: reached,
+                    last_notified: now,
+                    ..escalation
+                },
+            )
+            .unwrap();
+        }
+    }
+}
+
+pub fn schedule_escalations(config: &Config, args: &args::Args) {
+    if config.escalation_policies.is_empty() {
+        return;
+    }
+    let db_path = args.database.clone();
+    let dashboard_url = args.dashboard_url.clone();
+    let config = config.clone();
+    tokio::task::spawn(async move {
+        let connection = db::init_db(&db_path).unwrap();
+        let mut tick = tokio::time::interval(ESCALATION_CHECK_INTERVAL);
+        loop {
+            tick.tick().await;
+            resolve_escalations(&connection, &db_path, &config, &dashboard_url);
+            escalate_incidents(&connection, &db_path, &config, &dashboard_url);
+        }
+    });
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use rocket::tokio;
+use rocket::tokio::sync::broadcast::{self, channel, Receiver};
+use rocket::tokio::sync::mpsc;
+
+use crate::model::{Message, MonitoringTargetStatus, RecordedMessage};
+use crate::{args, db};
+
+/// Sends messages to the recorder, which stores each one before it is
+/// broadcast to the subscribers. Unlike a broadcast subscription, the queue
+/// to the recorder never drops messages, so every message gets an event id.
+#[derive(Clone)]
+pub struct EventSender {
+    recorder: mpsc::UnboundedSender<Message>,
+    broadcast: broadcast::Sender<Message>,
+}
+
+impl EventSender {
+    /// Queues the message for the recorder, which runs as long as the server.
+    pub fn send(&self, message: Message) {
+        let _ = self.recorder.send(message);
+    }
+
+    /// Receives the messages sent after subscribing, once they are stored.
+    pub fn subscribe(&self) -> Receiver<Message> {
+        self.broadcast.subscribe()
+    }
+}
+
+/// Stores every sent message with an increasing id, then broadcasts it to the
+/// subscribers of the returned sender and, with its id, to the returned
+/// queue, which `/events` subscribes to. Clients that reconnect or fall
+/// behind replay the stored messages they missed.
+pub fn record_events(args: &args::Args) -> (EventSender, broadcast::Sender<RecordedMessage>) {
+    let db_path = args.database.clone();
+    let connection = db::init_db(&db_path).unwrap();
+    let mut last_statuses: HashMap<String, MonitoringTargetStatus> = HashMap::new();
+    for recorded in db::get_last_transitions(&connection).unwrap() {
+        if let Message::Observation(observation) = recorded.message {
+            last_statuses.insert(
+                observation.monitoring_target.id,
+                observation.observed_status.status,
+            );
+        }
+    }
+    let (recorder, mut rx) = mpsc::unbounded_channel::<Message>();
+    let (message_sender, _) = channel::<Message>(1024);
+    let (recorded_sender, _) = channel::<RecordedMessage>(1024);
+    let event_sender = EventSender {
+        recorder,
+        broadcast: message_sender.clone(),
+    };
+    let sender = recorded_sender.clone();
+    tokio::task::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            let transition = match &message {
+                Message::Observation(observation) => {
+                    let status = &observation.observed_status.status;
+                    last_statuses
+                        .insert(observation.monitoring_target.id.clone(), status.clone())
+                        .as_ref()
+                        != Some(status)
+                }
+                _ => false,
+            };
+            let recorded = db::add_event(&connection, &message, transition).unwrap();
+            let _ = message_sender.send(message);
+            let _ = sender.send(recorded);
+        }
+    });
+    (event_sender, recorded_sender)
+}
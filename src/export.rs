@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rocket::serde::json::{json, Value};
+use rocket::tokio;
+
+use crate::metrics::target_labels;
+use crate::model::{Config, ExporterDescriptor, ExporterTypeDescriptor, Message, Observation};
+use crate::{args, db};
+
+const EXPORTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A sample of one observation, named like the gauges of `/metrics`.
+struct Sample {
+    name: &'static str,
+    labels: Vec<(String, String)>,
+    value: f64,
+    timestamp: DateTime<Utc>,
+}
+
+fn observation_samples(observation: &Observation) -> Vec<Sample> {
+    let target = &observation.monitoring_target;
+    let observed_status = &observation.observed_status;
+    let timestamp = observed_status.timestamp;
+    let status_name = observed_status.status.to_string();
+    let mut samples = vec![
+        Sample {
+            name: "observatory_target_status",
+            labels: target_labels(target, &[("status", &status_name)]),
+            value: 1.0,
+            timestamp,
+        },
+        Sample {
+            name: "observatory_target_severity",
+            labels: target_labels(target, &[]),
+            value: observed_status.status.severity() as f64,
+            timestamp,
+        },
+        Sample {
+            name: "observatory_target_last_check_duration_seconds",
+            labels: target_labels(target, &[]),
+            value: observed_status.duration as f64 / 1000.0,
+            timestamp,
+        },
+    ];
+    let mut metrics: Vec<(&String, &f64)> = observed_status.metrics.iter().collect();
+    metrics.sort_by_key(|(name, _)| *name);
+    for (name, value) in metrics {
+        samples.push(Sample {
+            name: "observatory_target_check_metric",
+            labels: target_labels(target, &[("metric", name)]),
+            value: *value,
+            timestamp,
+        });
+    }
+    samples
+}
+
+fn encode_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn encode_bytes_field(buffer: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    encode_varint(buffer, (field << 3) | 2);
+    encode_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+/// Encodes the samples as a snappy compressed remote-write `WriteRequest`
+/// protobuf with one time series per sample.
+fn encode_remote_write(samples: &[Sample]) -> Vec<u8> {
+    let mut request = vec![];
+    for sample in samples.iter() {
+        let mut labels = sample.labels.clone();
+        labels.push(("__name__".to_string(), sample.name.to_string()));
+        labels.sort();
+        let mut time_series = vec![];
+        for (name, value) in labels.iter() {
+            let mut label = vec![];
+            encode_bytes_field(&mut label, 1, name.as_bytes());
+            encode_bytes_field(&mut label, 2, value.as_bytes());
+            encode_bytes_field(&mut time_series, 1, &label);
+        }
+        let mut encoded_sample = vec![];
+        // value: double (fixed64), timestamp: int64 in milliseconds
+        encode_varint(&mut encoded_sample, (1 << 3) | 1);
+        encoded_sample.extend_from_slice(&sample.value.to_le_bytes());
+        encode_varint(&mut encoded_sample, 2 << 3);
+        encode_varint(
+            &mut encoded_sample,
+            sample.timestamp.timestamp_millis() as u64,
+        );
+        encode_bytes_field(&mut time_series, 2, &encoded_sample);
+        encode_bytes_field(&mut request, 1, &time_series);
+    }
+    snap::raw::Encoder::new().compress_vec(&request).unwrap()
+}
+
+/// Encodes the samples as an OTLP `ExportMetricsServiceRequest` in JSON, with
+/// one gauge per sample name.
+fn encode_otlp(samples: &[Sample]) -> Value {
+    let mut gauges: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+    for sample in samples.iter() {
+        let attributes: Vec<Value> = sample
+            .labels
+            .iter()
+            .map(|(name, value)| json!({"key": name, "value": {"stringValue": value}}))
+            .collect();
+        gauges.entry(sample.name).or_default().push(json!({
+            "attributes": attributes,
+            "timeUnixNano": sample.timestamp.timestamp_nanos_opt().unwrap_or_default().to_string(),
+            "asDouble": sample.value,
+        }));
+    }
+    let metrics: Vec<Value> = gauges
+        .into_iter()
+        .map(|(name, data_points)| json!({"name": name, "gauge": {"dataPoints": data_points}}))
+        .collect();
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": "observatory"}}],
+            },
+            "scopeMetrics": [{
+                "scope": {"name": "observatory"},
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+async fn push(exporter: &ExporterDescriptor, samples: &[Sample]) -> Result<(), reqwest::Error> {
+    let client = reqwest::Client::new();
+    let (url, headers) = match &exporter.exporter {
+        ExporterTypeDescriptor::RemoteWrite { url, headers }
+        | ExporterTypeDescriptor::Otlp { url, headers } => (url, headers),
+    };
+    let mut request = client.post(url).timeout(EXPORTER_TIMEOUT);
+    request = match &exporter.exporter {
+        ExporterTypeDescriptor::RemoteWrite { .. } => request
+            .header("Content-Type", "application/x-protobuf")
+            .header("Content-Encoding", "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(encode_remote_write(samples)),
+        ExporterTypeDescriptor::Otlp { .. } => request.json(&encode_otlp(samples)),
+    };
+    for (name, value) in headers.iter() {
+        request = request.header(name, value);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Pushes the samples, retrying with exponential backoff. Returns whether
+/// they were pushed.
+async fn push_with_retries(exporter: &ExporterDescriptor, samples: &[Sample]) -> bool {
+    let mut delay = Duration::from_secs(exporter.retry_delay);
+    let mut attempt = 0;
+    loop {
+        if push(exporter, samples).await.is_ok() {
+            return true;
+        }
+        if attempt >= exporter.retries {
+            return false;
+        }
+        attempt += 1;
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+/// Pushes new observations to every exporter on its interval, in batches of
+/// up to `batch_size` observations. The recorded observations carry the
+/// effective status, like `/metrics`. Observations that could not be pushed
+/// are pushed again on the next interval, as long as they are kept. The last
+/// pushed event is stored per exporter, so a restart resumes after it; a new
+/// exporter starts with the observations made after it was added.
+pub fn schedule_exports(config: &Config, args: &args::Args) {
+    for exporter in config.exporters.iter() {
+        let exporter = exporter.clone();
+        let db_path = args.database.clone();
+        tokio::task::spawn(async move {
+            let connection = db::init_db(&db_path).unwrap();
+            let mut pushed_until = match db::get_exporter_cursor(&connection, &exporter.id).unwrap()
+            {
+                Some(pushed_until) => pushed_until,
+                None => {
+                    let last_event_id = db::get_last_event_id(&connection).unwrap();
+                    db::set_exporter_cursor(&connection, &exporter.id, last_event_id).unwrap();
+                    last_event_id
+                }
+            };
+            loop {
+                tokio::time::sleep(Duration::from_secs(exporter.interval)).await;
+                loop {
+                    let events =
+                        db::get_event_batch(&connection, pushed_until, exporter.batch_size.max(1))
+                            .unwrap();
+                    let Some(last_event) = events.last() else {
+                        break;
+                    };
+                    let last_pushed = last_event.id;
+                    let samples: Vec<Sample> = events
+                        .iter()
+                        .filter_map(|recorded| match &recorded.message {
+                            Message::Observation(observation) => Some(observation),
+                            _ => None,
+                        })
+                        .flat_map(observation_samples)
+                        .collect();
+                    if !samples.is_empty() && !push_with_retries(&exporter, &samples).await {
+                        break;
+                    }
+                    db::set_exporter_cursor(&connection, &exporter.id, last_pushed).unwrap();
+                    pushed_until = last_pushed;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,47 @@
+use rusqlite::{Connection, Result};
+
+use crate::db;
+use crate::model::{Message, Observation};
+
+/// Records status changes of the observed target and decides whether it is
+/// flapping. Returns a message if the target started or stopped flapping with
+/// this observation. Statuses that are not notifiable, e.g. maintenance, are
+/// not counted as changes.
+pub fn track_observation(conn: &Connection, observation: &Observation) -> Result<Option<Message>> {
+    let target = &observation.monitoring_target;
+    let observed_status = &observation.observed_status;
+    let Some(flap_detection) = &target.flap_detection else {
+        return Ok(None);
+    };
+    if !observed_status.status.is_notifiable() {
+        return Ok(None);
+    }
+    let mut state = db::get_target_state(conn, &target.id)?;
+    if state
+        .last_status
+        .as_ref()
+        .is_some_and(|last_status| *last_status != observed_status.status)
+    {
+        state.transitions.push(observed_status.timestamp);
+    }
+    state.last_status = Some(observed_status.status.clone());
+    let window = chrono::Duration::seconds(flap_detection.window as i64);
+    state
+        .transitions
+        .retain(|transition| observed_status.timestamp - *transition <= window);
+    let flapping = state.transitions.len() >= flap_detection.threshold as usize;
+    let message = match (state.flapping, flapping) {
+        (false, true) => {
+            state.flapping_since = Some(observed_status.timestamp);
+            Some(Message::FlappingStarted(observation.clone()))
+        }
+        (true, false) => {
+            state.flapping_since = None;
+            Some(Message::FlappingStopped(observation.clone()))
+        }
+        _ => None,
+    };
+    state.flapping = flapping;
+    db::set_target_state(conn, &target.id, &state)?;
+    Ok(message)
+}
@@ -0,0 +1,79 @@
+use rusqlite::{Connection, Result};
+
+use crate::db;
+use crate::model::{MonitoringTargetStatus, Observation};
+
+/// Counts consecutive failed and healthy runs of the observed target and
+/// returns the observation with the target's effective status. A healthy
+/// target only fails after `failures_before_unhealthy` failed runs and a
+/// failed target only recovers after `successes_before_recovery` healthy runs.
+/// Until then a healthy target stays healthy, any other target is `Pending`,
+/// and a failed target keeps its failure. `Unknown` results are inconclusive:
+/// a failed target keeps its failure and the counts are kept. Other statuses,
+/// e.g. maintenance, take effect immediately and restart the counting.
+pub fn track_observation(conn: &Connection, observation: &Observation) -> Result<Observation> {
+    let target = &observation.monitoring_target;
+    let observed_status = &observation.observed_status;
+    let mut state = db::get_target_state(conn, &target.id)?;
+    let effective_is_problem = state
+        .effective_status
+        .as_ref()
+        .is_some_and(|status| status.is_problem());
+    let (effective_status, confirmation) = if observed_status.status.is_problem() {
+        state.consecutive_failures += 1;
+        state.consecutive_successes = 0;
+        if effective_is_problem || state.consecutive_failures >= target.failures_before_unhealthy {
+            (observed_status.status.clone(), None)
+        } else {
+            let confirmation = format!(
+                "{} of {} failures",
+                state.consecutive_failures, target.failures_before_unhealthy
+            );
+            let effective_status = match &state.effective_status {
+                Some(MonitoringTargetStatus::Healthy) => MonitoringTargetStatus::Healthy,
+                _ => MonitoringTargetStatus::Pending,
+            };
+            (effective_status, Some(confirmation))
+        }
+    } else if observed_status.status == MonitoringTargetStatus::Healthy {
+        state.consecutive_successes += 1;
+        state.consecutive_failures = 0;
+        if !effective_is_problem || state.consecutive_successes >= target.successes_before_recovery
+        {
+            (MonitoringTargetStatus::Healthy, None)
+        } else {
+            let confirmation = format!(
+                "{} of {} successes",
+                state.consecutive_successes, target.successes_before_recovery
+            );
+            (state.effective_status.clone().unwrap(), Some(confirmation))
+        }
+    } else if observed_status.status == MonitoringTargetStatus::Unknown {
+        if effective_is_problem {
+            (state.effective_status.clone().unwrap(), None)
+        } else {
+            (MonitoringTargetStatus::Unknown, None)
+        }
+    } else {
+        state.consecutive_failures = 0;
+        state.consecutive_successes = 0;
+        (observed_status.status.clone(), None)
+    };
+    if state.effective_status.as_ref() != Some(&effective_status) {
+        state.effective_status = Some(effective_status.clone());
+        state.since = Some(observed_status.timestamp);
+    }
+    db::set_target_state(conn, &target.id, &state)?;
+
+    let mut effective_observation = observation.clone();
+    effective_observation.observed_status.status = effective_status;
+    if let Some(confirmation) = confirmation {
+        effective_observation.observed_status.description =
+            if observed_status.description.is_empty() {
+                confirmation
+            } else {
+                format!("{} ({})", observed_status.description, confirmation)
+            };
+    }
+    Ok(effective_observation)
+}
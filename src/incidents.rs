@@ -0,0 +1,38 @@
+use rusqlite::{Connection, Result};
+
+use crate::db;
+use crate::model::{Message, MonitoringTargetStatus, Observation};
+
+/// Opens, extends or closes the incident of the observed target. Returns a
+/// message if an incident was opened or closed by this observation.
+/// Only `Healthy` closes incidents and only problems open them, so e.g.
+/// observations during maintenance are ignored.
+pub fn track_observation(conn: &Connection, observation: &Observation) -> Result<Option<Message>> {
+    let observed_status = &observation.observed_status;
+    if observed_status.status != MonitoringTargetStatus::Healthy
+        && !observed_status.status.is_problem()
+    {
+        return Ok(None);
+    }
+    let open_incident = db::get_open_incident(conn, &observation.monitoring_target.id)?;
+    match open_incident {
+        None if observed_status.status == MonitoringTargetStatus::Healthy => Ok(None),
+        None => {
+            let incident = db::open_incident(conn, observation)?;
+            Ok(Some(Message::IncidentOpened(incident)))
+        }
+        Some(mut incident) if observed_status.status == MonitoringTargetStatus::Healthy => {
+            incident.end = Some(observed_status.timestamp);
+            db::update_incident(conn, &incident)?;
+            Ok(Some(Message::IncidentClosed(incident)))
+        }
+        Some(mut incident) => {
+            incident.observations += 1;
+            if observed_status.status.severity() > incident.worst_status.severity() {
+                incident.worst_status = observed_status.status.clone();
+            }
+            db::update_incident(conn, &incident)?;
+            Ok(None)
+        }
+    }
+}
@@ -4,22 +4,32 @@ extern crate rocket;
 use rocket::figment::providers::Env;
 use rocket::figment::Figment;
 use rocket::fs::FileServer;
-use rocket::tokio::sync::broadcast::channel;
 use rocket::Config;
 
 pub mod model;
 use clap::Parser;
-use model::Message;
 
 pub mod args;
+pub mod calendar;
 pub mod checks;
+pub mod composite;
+pub mod config;
 pub mod db;
+pub mod escalation;
+pub mod events;
+pub mod export;
+pub mod flapping;
+pub mod hysteresis;
+pub mod incidents;
+pub mod maintenance;
+pub mod metrics;
+pub mod notify;
 pub mod paths;
 pub mod schedule;
+pub mod templates;
 
 /// Returns an infinite stream of server-sent events. Each event is a message
 /// pulled from a broadcast queue sent by the `post` handler.
-
 /// Receive a message from a form submission and broadcast it to any receivers.
 // #[post("/message", data = "<form>")]
 // fn post(form: Form<Message>, queue: &State<Sender<Message>>) {
@@ -30,32 +40,56 @@ pub mod schedule;
 #[rocket::main]
 async fn main() {
     let args = args::Args::parse();
-    let event_stream = channel::<Message>(1024);
+    db::migrate_db(&args.database).unwrap();
+    let config = config::load_config(&args);
+    let (event_sender, recorded_events) = events::record_events(&args);
     schedule::schedule_cleanup(&args);
-    schedule::schedule_checks(event_stream.0.clone(), &args);
+    notify::schedule_notifications(&event_sender, &config, &args);
+    escalation::schedule_escalations(&config, &args);
+    export::schedule_exports(&config, &args);
+    let schedulers = schedule::schedule_checks(event_sender.clone(), &config, &args);
 
     let mut rocket_config = Figment::from(Config::default())
         .merge(Env::prefixed("OBSERVATORY_").ignore(&["PROFILE"]).global());
-    if args.port.is_some() {
-        rocket_config = rocket_config.merge((Config::PORT, args.port.unwrap()));
+    if let Some(port) = args.port {
+        rocket_config = rocket_config.merge((Config::PORT, port));
     }
-    if args.address.is_some() {
-        rocket_config = rocket_config.merge((Config::ADDRESS, args.address.clone().unwrap()));
+    if let Some(address) = &args.address {
+        rocket_config = rocket_config.merge((Config::ADDRESS, address.clone()));
     }
 
     let file_server = FileServer::from(&args.website);
 
     let rocket = rocket::build()
         .configure(rocket_config)
-        .manage(event_stream.0)
+        .manage(event_sender)
+        .manage(recorded_events)
         .manage(args)
+        .manage(config)
+        .manage(schedulers)
         .mount(
             "/",
             routes![
                 paths::events,
+                paths::websocket,
                 paths::targets,
+                paths::check_target,
+                paths::pause_target,
+                paths::resume_target,
                 paths::status,
-                paths::observations
+                paths::statuses,
+                paths::metrics,
+                paths::dependencies,
+                paths::observations,
+                paths::incidents,
+                paths::target_incidents,
+                paths::notification_deliveries,
+                paths::acknowledge,
+                paths::silences,
+                paths::create_silence,
+                paths::delete_silence,
+                paths::maintenance_windows,
+                paths::uptime
             ],
         )
         .mount("/", file_server);
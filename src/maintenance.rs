@@ -0,0 +1,67 @@
+use chrono::{DateTime, Utc};
+
+use crate::model::{
+    MaintenanceScheduleDescriptor, MaintenanceWindow, MaintenanceWindowDescriptor,
+    MonitoringTargetDescriptor,
+};
+
+/// Start and end of the occurrence that is active at `at`, or of the next one
+/// if none is active.
+pub fn current_or_next_occurrence(
+    window: &MaintenanceWindowDescriptor,
+    at: DateTime<Utc>,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    match &window.schedule {
+        MaintenanceScheduleDescriptor::OneOff { start, end } => {
+            (*end > at).then_some((*start, *end))
+        }
+        MaintenanceScheduleDescriptor::Recurring {
+            cron,
+            duration,
+            timezone,
+        } => {
+            let duration = chrono::Duration::minutes(*duration as i64);
+            let timezone = timezone.unwrap_or(chrono_tz::UTC);
+            // The first occurrence that has not ended yet.
+            let start = cron.next_after(at - duration, &timezone)?;
+            Some((start, start + duration))
+        }
+    }
+}
+
+pub fn is_active(window: &MaintenanceWindowDescriptor, at: DateTime<Utc>) -> bool {
+    current_or_next_occurrence(window, at).is_some_and(|(start, end)| start <= at && at < end)
+}
+
+pub fn in_maintenance(
+    windows: &[MaintenanceWindowDescriptor],
+    target: &MonitoringTargetDescriptor,
+    at: DateTime<Utc>,
+) -> bool {
+    windows
+        .iter()
+        .any(|window| window.is_attached_to(target) && is_active(window, at))
+}
+
+/// Active and upcoming windows, sorted by start.
+pub fn get_maintenance_windows(
+    windows: &[MaintenanceWindowDescriptor],
+    at: DateTime<Utc>,
+    active_only: bool,
+) -> Vec<MaintenanceWindow> {
+    let mut maintenance_windows: Vec<MaintenanceWindow> = windows
+        .iter()
+        .filter_map(|window| {
+            let (start, end) = current_or_next_occurrence(window, at)?;
+            Some(MaintenanceWindow {
+                descriptor: window.clone(),
+                start,
+                end,
+                active: start <= at,
+            })
+        })
+        .filter(|window| window.active || !active_only)
+        .collect();
+    maintenance_windows.sort_by_key(|window| window.start);
+    maintenance_windows
+}
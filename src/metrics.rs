@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::model::{
+    CheckCounters, MonitoringTargetDescriptor, MonitoringTargetState,
+    ObservedMonitoringTargetStatus,
+};
+
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// A metric family with one sample per target.
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Labels of the target's samples: its id, name, type and comma separated
+/// tags, followed by `extra`.
+pub fn target_labels(
+    target: &MonitoringTargetDescriptor,
+    extra: &[(&str, &str)],
+) -> Vec<(String, String)> {
+    let mut labels = vec![
+        ("id".to_string(), target.id.clone()),
+        ("name".to_string(), target.name.clone()),
+        ("type".to_string(), target.target.type_name()),
+        ("tags".to_string(), target.tags.join(",")),
+    ];
+    labels.extend(
+        extra
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+    );
+    labels
+}
+
+/// Formats a sample value, spelling infinities and NaN the way both formats
+/// expect instead of Rust's `inf` and `NaN`.
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn labels(target: &MonitoringTargetDescriptor, extra: &[(&str, &str)]) -> String {
+    let labels: Vec<String> = target_labels(target, extra)
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Renders the status of every target in the Prometheus text format, or in
+/// the OpenMetrics format if `openmetrics` is set. Counters are named without
+/// the `_total` suffix in the OpenMetrics metadata, as the format requires.
+pub fn render(
+    statuses: &[(
+        MonitoringTargetDescriptor,
+        Option<ObservedMonitoringTargetStatus>,
+        MonitoringTargetState,
+    )],
+    counters: &HashMap<String, CheckCounters>,
+    openmetrics: bool,
+) -> String {
+    let mut families = [
+        Family {
+            name: "observatory_target_status",
+            kind: "gauge",
+            help: "Effective status of the target, 1 for the current status.",
+            samples: vec![],
+        },
+        Family {
+            name: "observatory_target_severity",
+            kind: "gauge",
+            help: "Severity of the effective status, from 0 (healthy) to 3 (unhealthy).",
+            samples: vec![],
+        },
+        Family {
+            name: "observatory_target_last_check_timestamp_seconds",
+            kind: "gauge",
+            help: "Time of the last check.",
+            samples: vec![],
+        },
+        Family {
+            name: "observatory_target_last_check_duration_seconds",
+            kind: "gauge",
+            help: "Duration of the last check.",
+            samples: vec![],
+        },
+        Family {
+            name: "observatory_target_check_metric",
+            kind: "gauge",
+            help: "Numeric result of the last check.",
+            samples: vec![],
+        },
+        Family {
+            name: "observatory_target_checks_total",
+            kind: "counter",
+            help: "Checks run.",
+            samples: vec![],
+        },
+        Family {
+            name: "observatory_target_failures_total",
+            kind: "counter",
+            help: "Checks that found a problem.",
+            samples: vec![],
+        },
+        Family {
+            name: "observatory_target_retries_total",
+            kind: "counter",
+            help: "Retried check attempts.",
+            samples: vec![],
+        },
+    ];
+    for (target, observed_status, state) in statuses.iter() {
+        let target_labels = labels(target, &[]);
+        let status = state
+            .effective_status
+            .clone()
+            .or_else(|| observed_status.as_ref().map(|status| status.status.clone()));
+        if let Some(status) = status {
+            let status_name = status.to_string();
+            families[0]
+                .samples
+                .push((labels(target, &[("status", &status_name)]), 1.0));
+            families[1]
+                .samples
+                .push((target_labels.clone(), status.severity() as f64));
+        }
+        if let Some(observed_status) = observed_status {
+            families[2].samples.push((
+                target_labels.clone(),
+                observed_status.timestamp.timestamp_millis() as f64 / 1000.0,
+            ));
+            families[3].samples.push((
+                target_labels.clone(),
+                observed_status.duration as f64 / 1000.0,
+            ));
+            let mut metrics: Vec<(&String, &f64)> = observed_status.metrics.iter().collect();
+            metrics.sort_by_key(|(name, _)| *name);
+            for (name, value) in metrics {
+                families[4]
+                    .samples
+                    .push((labels(target, &[("metric", name)]), *value));
+            }
+        }
+        let counters = counters.get(&target.id).cloned().unwrap_or_default();
+        families[5]
+            .samples
+            .push((target_labels.clone(), counters.checks as f64));
+        families[6]
+            .samples
+            .push((target_labels.clone(), counters.failures as f64));
+        families[7]
+            .samples
+            .push((target_labels, counters.retries as f64));
+    }
+
+    let mut output = String::new();
+    for family in families.iter() {
+        let metadata_name = if openmetrics && family.kind == "counter" {
+            family.name.trim_end_matches("_total")
+        } else {
+            family.name
+        };
+        writeln!(output, "# HELP {} {}", metadata_name, family.help).unwrap();
+        writeln!(output, "# TYPE {} {}", metadata_name, family.kind).unwrap();
+        for (labels, value) in family.samples.iter() {
+            writeln!(output, "{}{} {}", family.name, labels, format_value(*value)).unwrap();
+        }
+    }
+    if openmetrics {
+        output.push_str("# EOF\n");
+    }
+    output
+}
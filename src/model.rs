@@ -1,15 +1,87 @@
-use chrono::DateTime;
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{DateTime, NaiveTime, Weekday};
 
 use chrono::Utc;
 use rocket::serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+use crate::calendar::CronExpression;
+use crate::templates::Template;
+use chrono_tz::Tz;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 pub enum MonitoringTargetStatus {
-    #[default]
     Healthy,
     Unhealthy,
     Degraded,
+    /// A failed check during a maintenance window.
+    Maintenance,
+    /// The check could not be run or evaluated, e.g. because `systemctl` is
+    /// missing. Retried like a failure, but neither opens nor closes
+    /// incidents.
+    Unknown,
+    /// No conclusive result yet: the target was never checked, its first
+    /// failure is not confirmed yet or a failed attempt is being retried.
+    /// Retried attempts are only reported in `Message::RetryAttempt` and never
+    /// stored as observations.
+    Pending,
+    /// The target is paused and not checked.
+    Paused,
+    /// A failed check while a target it depends on is failing, so the
+    /// failure is attributed to the dependency.
+    Unreachable,
+}
+
+impl MonitoringTargetStatus {
+    /// Orders statuses from best (`Healthy`) to worst (`Unhealthy`).
+    pub fn severity(&self) -> u8 {
+        match self {
+            MonitoringTargetStatus::Healthy
+            | MonitoringTargetStatus::Maintenance
+            | MonitoringTargetStatus::Pending
+            | MonitoringTargetStatus::Paused => 0,
+            MonitoringTargetStatus::Unknown | MonitoringTargetStatus::Unreachable => 1,
+            MonitoringTargetStatus::Degraded => 2,
+            MonitoringTargetStatus::Unhealthy => 3,
+        }
+    }
+
+    /// Whether the status opens or extends an incident.
+    pub fn is_problem(&self) -> bool {
+        matches!(
+            self,
+            MonitoringTargetStatus::Unhealthy | MonitoringTargetStatus::Degraded
+        )
+    }
+
+    /// Whether observations with this status are part of uptime statistics.
+    pub fn counts_for_uptime(&self) -> bool {
+        matches!(
+            self,
+            MonitoringTargetStatus::Healthy
+                | MonitoringTargetStatus::Unhealthy
+                | MonitoringTargetStatus::Degraded
+        )
+    }
+
+    /// Whether a change to this status is sent to notifiers.
+    pub fn is_notifiable(&self) -> bool {
+        !matches!(
+            self,
+            MonitoringTargetStatus::Maintenance
+                | MonitoringTargetStatus::Pending
+                | MonitoringTargetStatus::Paused
+                | MonitoringTargetStatus::Unreachable
+        )
+    }
+}
+
+impl fmt::Display for MonitoringTargetStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,6 +89,9 @@ pub enum MonitoringTargetStatus {
 pub struct CheckedMonitoringTargetStatus {
     pub status: MonitoringTargetStatus,
     pub description: String,
+    /// Numeric results of the check, e.g. `rtt_ms` of a ping.
+    #[serde(default)]
+    pub metrics: HashMap<String, f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,25 +101,237 @@ pub struct ObservedMonitoringTargetStatus {
     pub status: MonitoringTargetStatus,
     pub description: String,
     pub retries: u8,
+    /// Time the check waited for a free slot before it ran.
+    #[serde(default)]
+    pub queue_time: u64, // in milliseconds
+    /// Time the check itself took.
+    #[serde(default)]
+    pub duration: u64, // in milliseconds
+    #[serde(default)]
+    pub metrics: HashMap<String, f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde", tag = "type")]
 pub enum MonitoringTargetTypeDescriptor {
-    HTTP { url: String },
-    Systemd { unit: String },
-    Ping { target: String },
-    FSSpace { path: String },
+    HTTP {
+        url: String,
+    },
+    Systemd {
+        unit: String,
+    },
+    Ping {
+        target: String,
+    },
+    FSSpace {
+        path: String,
+    },
+    /// Status computed from the effective statuses of other targets whenever
+    /// one of them is observed.
+    Composite {
+        members: Vec<String>,
+        rule: CompositeRuleDescriptor,
+    },
+    /// Scrapes a Prometheus text format endpoint and evaluates the samples
+    /// of `metric` whose labels equal `labels`.
+    PrometheusMetric {
+        url: String,
+        metric: String,
+        #[serde(default)]
+        labels: HashMap<String, String>,
+        thresholds: Vec<MetricThresholdDescriptor>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum ComparisonOperator {
+    #[serde(rename = ">")]
+    Greater,
+    #[serde(rename = ">=")]
+    GreaterOrEqual,
+    #[serde(rename = "<")]
+    Less,
+    #[serde(rename = "<=")]
+    LessOrEqual,
+    #[serde(rename = "==")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+}
+
+impl ComparisonOperator {
+    pub fn compare(&self, left: f64, right: f64) -> bool {
+        match self {
+            ComparisonOperator::Greater => left > right,
+            ComparisonOperator::GreaterOrEqual => left >= right,
+            ComparisonOperator::Less => left < right,
+            ComparisonOperator::LessOrEqual => left <= right,
+            ComparisonOperator::Equal => left == right,
+            ComparisonOperator::NotEqual => left != right,
+        }
+    }
+}
+
+impl fmt::Display for ComparisonOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operator = match self {
+            ComparisonOperator::Greater => ">",
+            ComparisonOperator::GreaterOrEqual => ">=",
+            ComparisonOperator::Less => "<",
+            ComparisonOperator::LessOrEqual => "<=",
+            ComparisonOperator::Equal => "==",
+            ComparisonOperator::NotEqual => "!=",
+        };
+        f.write_str(operator)
+    }
+}
+
+/// A sample matching `operator` and `value`, e.g. `> 1000`, sets the status
+/// of the target. The worst status of all matching thresholds wins, the
+/// target is healthy if none matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MetricThresholdDescriptor {
+    pub operator: ComparisonOperator,
+    pub value: f64,
+    pub status: MonitoringTargetStatus,
+}
+
+/// How the status of a composite target is computed. Only members that are
+/// healthy or failing are considered, e.g. paused members are ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type")]
+pub enum CompositeRuleDescriptor {
+    /// Healthy if every member is healthy, otherwise unhealthy.
+    AllHealthy,
+    /// Healthy if at least `count` members are healthy, otherwise unhealthy.
+    AtLeast { count: usize },
+    /// The status of the worst member.
+    WorstOf,
+    /// The status of the best member.
+    BestOf,
+    /// Healthy if the healthy members make up at least `threshold` (0 to 1)
+    /// of the total weight, degraded if some are healthy, otherwise
+    /// unhealthy. Members without a weight count as 1.
+    Weighted {
+        #[serde(default)]
+        weights: HashMap<String, f64>,
+        threshold: f64,
+    },
+}
+
+impl MonitoringTargetTypeDescriptor {
+    /// The `type` tag of the check, e.g. `HTTP`.
+    pub fn type_name(&self) -> String {
+        let value = rocket::serde::json::serde_json::to_value(self).unwrap();
+        value["type"].as_str().unwrap_or_default().to_string()
+    }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct MonitoringTargetDescriptor {
     pub id: String,
     pub name: String,
+    #[serde(default = "default_interval")]
     pub interval: u64, // in seconds
+    /// Runs the check at the occurrences of the cron expression instead of
+    /// every `interval`.
+    pub schedule: Option<CronExpression>,
+    /// Timezone of `schedule` and `active_hours`, defaults to UTC.
+    pub timezone: Option<Tz>,
+    /// Only runs the check within these hours.
+    pub active_hours: Option<ActiveHoursDescriptor>,
     pub retries: u8,
+    /// Delay between retries, defaults to `interval`.
+    pub retry_interval: Option<u64>, // in seconds
+    /// Doubles the retry delay after every retry.
+    #[serde(default)]
+    pub retry_backoff: bool,
+    /// Upper bound of a random delay added to every retry.
+    #[serde(default)]
+    pub retry_jitter: u64, // in seconds
+    /// Upper bound of a random delay before every run, to spread the load of
+    /// targets with the same schedule.
+    #[serde(default)]
+    pub jitter: u64, // in seconds
     pub timeout: u64, // in seconds
     pub target: MonitoringTargetTypeDescriptor,
+    pub group: Option<String>, // slash separated path, e.g. `network/core`
+    /// Free-form labels, e.g. `prod` or `team-a`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Ids of targets this target needs to be reachable, e.g. the ping of
+    /// its host.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    pub flap_detection: Option<FlapDetectionDescriptor>,
+    /// Consecutive failed runs before a healthy target is considered failed.
+    #[serde(default = "default_confirmation_count")]
+    pub failures_before_unhealthy: u32,
+    /// Consecutive healthy runs before a failed target is considered healthy.
+    #[serde(default = "default_confirmation_count")]
+    pub successes_before_recovery: u32,
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_confirmation_count() -> u32 {
+    1
+}
+
+/// Daily time range, e.g. `09:00` to `17:00`. The range wraps around midnight
+/// if `end` is before `start`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ActiveHoursDescriptor {
+    /// Days on which the range starts, every day if empty.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+/// A target is flapping while its status changed at least `threshold` times
+/// within the last `window` seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FlapDetectionDescriptor {
+    pub threshold: u32,
+    pub window: u64, // in seconds
+}
+
+impl MonitoringTargetDescriptor {
+    /// Whether the target is listed in `targets`, belongs to one of `groups`
+    /// or has one of `tags`. Selecting neither targets, groups nor tags
+    /// selects every target.
+    pub fn is_selected_by(&self, targets: &[String], groups: &[String], tags: &[String]) -> bool {
+        (targets.is_empty() && groups.is_empty() && tags.is_empty())
+            || targets.contains(&self.id)
+            || groups.iter().any(|group| self.in_group(group))
+            || tags.iter().any(|tag| self.tags.contains(tag))
+    }
+
+    /// Whether the target has `tag` and belongs to `group`, if given. Used to
+    /// filter API responses.
+    pub fn matches_filter(&self, tag: Option<&str>, group: Option<&str>) -> bool {
+        tag.is_none_or(|tag| self.tags.iter().any(|own_tag| own_tag == tag))
+            && group.is_none_or(|group| self.in_group(group))
+    }
+
+    /// Whether the target belongs to `group` or one of its subgroups.
+    pub fn in_group(&self, group: &str) -> bool {
+        let group = group.trim_matches('/');
+        match &self.group {
+            Some(own_group) => {
+                let own_group = own_group.trim_matches('/');
+                own_group == group || own_group.starts_with(&format!("{}/", group))
+            }
+            None => false,
+        }
+    }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -60,9 +347,572 @@ pub struct Observation {
     pub monitoring_target: MonitoringTargetDescriptor,
 }
 
+/// A period during which a target was not `Healthy`. `end` is `None` while
+/// the incident is still open.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Incident {
+    pub id: i64,
+    pub monitoring_target_id: String,
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    pub worst_status: MonitoringTargetStatus,
+    pub description: String,
+    pub observations: u32,
+    pub acknowledgement: Option<Acknowledgement>,
+    /// Silences that overlapped the incident.
+    #[serde(default)]
+    pub silences: Vec<Silence>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Acknowledgement {
+    pub timestamp: DateTime<Utc>,
+    pub author: String,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AcknowledgementRequest {
+    pub author: String,
+    #[serde(default)]
+    pub comment: String,
+}
+
+/// Suppresses notifications for a target or a group between `start` and
+/// `end`. Observations are still recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Silence {
+    #[serde(default)]
+    pub id: i64,
+    pub monitoring_target_id: Option<String>,
+    pub group: Option<String>,
+    pub start: Option<DateTime<Utc>>,
+    pub end: DateTime<Utc>,
+    pub author: String,
+    #[serde(default)]
+    pub comment: String,
+}
+
+impl Silence {
+    pub fn applies_to(&self, target: &MonitoringTargetDescriptor) -> bool {
+        self.monitoring_target_id.as_ref() == Some(&target.id)
+            || self
+                .group
+                .as_ref()
+                .is_some_and(|group| target.in_group(group))
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum SmtpTls {
+    None,
+    #[default]
+    StartTls,
+    Tls,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EmailNotifierDescriptor {
+    pub host: String,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+fn default_ntfy_url() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type")]
+pub enum NotifierTypeDescriptor {
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    Email(EmailNotifierDescriptor),
+    Ntfy {
+        #[serde(default = "default_ntfy_url")]
+        url: String,
+        topic: String,
+        token: Option<String>,
+    },
+    Gotify {
+        url: String,
+        token: String,
+    },
+    Matrix {
+        homeserver: String,
+        room_id: String,
+        access_token: String,
+    },
+    Slack {
+        url: String,
+    },
+    Discord {
+        url: String,
+    },
+}
+
+fn default_notifier_retries() -> u8 {
+    3
+}
+
+fn default_notifier_retry_delay() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NotifierDescriptor {
+    pub id: String,
+    #[serde(default = "default_notifier_retries")]
+    pub retries: u8,
+    #[serde(default = "default_notifier_retry_delay")]
+    pub retry_delay: u64, // in seconds, doubled after every failed attempt
+    pub notifier: NotifierTypeDescriptor,
+    pub title: Option<Template>,
+    pub body: Option<Template>,
+    /// Target ids this notifier is attached to.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// Groups this notifier is attached to, including their subgroups.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Tags of targets this notifier is attached to.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl NotifierDescriptor {
+    /// A notifier without any targets, groups or tags is attached to every target.
+    pub fn is_attached_to(&self, target: &MonitoringTargetDescriptor) -> bool {
+        target.is_selected_by(&self.targets, &self.groups, &self.tags)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EscalationLevelDescriptor {
+    pub after: u64, // in minutes since the incident started
+    pub notifiers: Vec<String>,
+}
+
+/// Notifiers used by an escalation policy are only notified through it and
+/// no longer on every status transition.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EscalationPolicyDescriptor {
+    pub id: String,
+    pub levels: Vec<EscalationLevelDescriptor>,
+    pub repeat_interval: Option<u64>, // in minutes
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl EscalationPolicyDescriptor {
+    /// A policy without any targets, groups or tags applies to every target.
+    pub fn is_attached_to(&self, target: &MonitoringTargetDescriptor) -> bool {
+        target.is_selected_by(&self.targets, &self.groups, &self.tags)
+    }
+}
+
+/// Progress of an escalation policy for an incident. `level` is the number of
+/// levels that have been notified.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Escalation {
+    pub incident_id: i64,
+    pub policy_id: String,
+    pub level: u32,
+    pub last_notified: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type")]
+pub enum MaintenanceScheduleDescriptor {
+    OneOff {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    Recurring {
+        cron: CronExpression,
+        duration: u64, // in minutes
+        timezone: Option<Tz>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MaintenanceWindowDescriptor {
+    pub id: String,
+    #[serde(default)]
+    pub description: String,
+    pub schedule: MaintenanceScheduleDescriptor,
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl MaintenanceWindowDescriptor {
+    /// A window without any targets, groups or tags applies to every target.
+    pub fn is_attached_to(&self, target: &MonitoringTargetDescriptor) -> bool {
+        target.is_selected_by(&self.targets, &self.groups, &self.tags)
+    }
+}
+
+/// The current or next occurrence of a maintenance window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MaintenanceWindow {
+    pub descriptor: MaintenanceWindowDescriptor,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Uptime {
+    pub monitoring_target_id: String,
+    pub since: DateTime<Utc>,
+    /// Observations taken into account, excluding e.g. maintenance.
+    pub observations: u32,
+    pub healthy: u32,
+    pub uptime: Option<f64>,
+}
+
+/// A target in the dependency graph.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DependencyNode {
+    pub id: String,
+    pub name: String,
+    pub effective_status: Option<MonitoringTargetStatus>,
+}
+
+/// `monitoring_target_id` depends on `depends_on`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DependencyEdge {
+    pub monitoring_target_id: String,
+    pub depends_on: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DependencyGraph {
+    pub nodes: Vec<DependencyNode>,
+    pub edges: Vec<DependencyEdge>,
+}
+
+/// State derived from the observations of a target.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MonitoringTargetState {
+    /// The status after applying `failures_before_unhealthy` and
+    /// `successes_before_recovery` to the observed statuses.
+    pub effective_status: Option<MonitoringTargetStatus>,
+    /// When the effective status last changed.
+    pub since: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    #[serde(skip)]
+    pub last_status: Option<MonitoringTargetStatus>,
+    pub flapping: bool,
+    pub flapping_since: Option<DateTime<Utc>>,
+    /// Status changes within the flap detection window.
+    pub transitions: Vec<DateTime<Utc>>,
+}
+
+/// Checks run for a target since it was first checked. `failures` counts
+/// checks that found a problem, `retries` the retried attempts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CheckCounters {
+    pub checks: u64,
+    pub failures: u64,
+    pub retries: u64,
+}
+
+/// The last observation of a target together with its state. Targets that
+/// were not checked yet have no observation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TargetStatus {
+    pub monitoring_target_id: String,
+    #[serde(flatten)]
+    pub observed_status: Option<ObservedMonitoringTargetStatus>,
+    #[serde(flatten)]
+    pub state: MonitoringTargetState,
+}
+
+/// Limits how many checks run at once.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SchedulingDescriptor {
+    /// Delays the first run of every interval target by a random offset of
+    /// up to this many seconds, at most its interval.
+    #[serde(default)]
+    pub start_spread: u64, // in seconds
+    pub max_concurrent_checks: Option<usize>,
+    /// Limits by check type, e.g. `{"Ping": 10}`.
+    #[serde(default)]
+    pub max_concurrent_checks_per_type: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Config {
+    pub targets: Vec<MonitoringTargetDescriptor>,
+    #[serde(default)]
+    pub notifiers: Vec<NotifierDescriptor>,
+    #[serde(default)]
+    pub escalation_policies: Vec<EscalationPolicyDescriptor>,
+    #[serde(default)]
+    pub maintenance_windows: Vec<MaintenanceWindowDescriptor>,
+    #[serde(default)]
+    pub scheduling: SchedulingDescriptor,
+    #[serde(default)]
+    pub exporters: Vec<ExporterDescriptor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type")]
+pub enum ExporterTypeDescriptor {
+    /// Prometheus remote-write endpoint, e.g. `http://prometheus:9090/api/v1/write`.
+    RemoteWrite {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    /// OTLP/HTTP metrics endpoint using JSON, e.g. `http://collector:4318/v1/metrics`.
+    Otlp {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+}
+
+fn default_export_interval() -> u64 {
+    60
+}
+
+fn default_export_batch_size() -> usize {
+    500
+}
+
+fn default_export_retries() -> u8 {
+    3
+}
+
+fn default_export_retry_delay() -> u64 {
+    5
+}
+
+/// Pushes the observations of all targets as metrics, for instances that
+/// cannot be scraped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ExporterDescriptor {
+    pub id: String,
+    #[serde(default = "default_export_interval")]
+    pub interval: u64, // in seconds
+    /// Observations per request.
+    #[serde(default = "default_export_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_export_retries")]
+    pub retries: u8,
+    #[serde(default = "default_export_retry_delay")]
+    pub retry_delay: u64, // in seconds, doubled after every failed attempt
+    pub exporter: ExporterTypeDescriptor,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type")]
+pub enum NotificationKind {
+    Transition,
+    Escalation { level: u32 },
+    Reminder,
+    Resolved,
+    FlappingStarted,
+    FlappingStopped,
+}
+
+impl NotificationKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NotificationKind::Transition => "Transition",
+            NotificationKind::Escalation { .. } => "Escalation",
+            NotificationKind::Reminder => "Reminder",
+            NotificationKind::Resolved => "Resolved",
+            NotificationKind::FlappingStarted => "FlappingStarted",
+            NotificationKind::FlappingStopped => "FlappingStopped",
+        }
+    }
+}
+
+/// Sent to notifiers when a target changes its status or an incident is
+/// escalated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub monitoring_target: MonitoringTargetDescriptor,
+    pub previous_status: Option<ObservedMonitoringTargetStatus>,
+    pub observed_status: ObservedMonitoringTargetStatus,
+    pub incident: Option<Incident>,
+    /// Link to the target on the dashboard, if `--dashboard-url` is set.
+    pub dashboard_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NotificationDelivery {
+    pub id: i64,
+    pub notifier_id: String,
+    pub monitoring_target_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub attempt: u8,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde", tag = "type")]
 pub enum Message {
+    /// Carries the effective status of the target, while the stored
+    /// observation keeps the result of the check.
     Observation(Observation),
+    /// A failed attempt that is retried, with the `Pending` status. It does
+    /// not change the status of the target.
+    RetryAttempt(Observation),
+    IncidentOpened(Incident),
+    IncidentClosed(Incident),
+    IncidentAcknowledged(Incident),
+    SilenceCreated(Silence),
+    SilenceEnded(Silence),
+    FlappingStarted(Observation),
+    FlappingStopped(Observation),
     AppUpdate,
 }
+
+/// A broadcast message as stored for replay to clients that reconnect.
+/// `transition` marks observations that changed the status of their target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RecordedMessage {
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub transition: bool,
+    pub message: Message,
+}
+
+impl Message {
+    /// Id of the target the message is about, if any.
+    pub fn monitoring_target_id(&self) -> Option<&str> {
+        match self {
+            Message::Observation(observation)
+            | Message::RetryAttempt(observation)
+            | Message::FlappingStarted(observation)
+            | Message::FlappingStopped(observation) => Some(&observation.monitoring_target.id),
+            Message::IncidentOpened(incident)
+            | Message::IncidentClosed(incident)
+            | Message::IncidentAcknowledged(incident) => Some(&incident.monitoring_target_id),
+            Message::SilenceCreated(silence) | Message::SilenceEnded(silence) => {
+                silence.monitoring_target_id.as_deref()
+            }
+            Message::AppUpdate => None,
+        }
+    }
+}
+
+/// Version of the WebSocket protocol at `/ws`, announced in
+/// `ServerMessage::Hello`. Incompatible changes to `ClientRequest` or
+/// `ServerMessage` increase it.
+pub const WEBSOCKET_PROTOCOL_VERSION: u32 = 1;
+
+/// A command sent by a WebSocket client. The optional `request_id` is echoed
+/// in the reply, e.g. `{"type": "Check", "request_id": 1, "monitoring_target_id": "web"}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ClientRequest {
+    #[serde(default)]
+    pub request_id: Option<u64>,
+    #[serde(flatten)]
+    pub command: ClientCommand,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type")]
+pub enum ClientCommand {
+    /// Adds targets to the subscription, all targets if empty. Clients start
+    /// subscribed to all targets.
+    Subscribe {
+        #[serde(default)]
+        targets: Vec<String>,
+    },
+    /// Removes targets from the subscription, all targets if empty.
+    Unsubscribe {
+        #[serde(default)]
+        targets: Vec<String>,
+    },
+    /// Checks a target now, like `POST /targets/<id>/check`.
+    Check { monitoring_target_id: String },
+    /// Acknowledges the open incident of a target, like
+    /// `POST /incidents/<id>/acknowledge`.
+    Acknowledge {
+        monitoring_target_id: String,
+        #[serde(flatten)]
+        acknowledgement: AcknowledgementRequest,
+    },
+}
+
+/// A message sent to a WebSocket client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type")]
+pub enum ServerMessage {
+    /// First message of every connection.
+    Hello { version: u32 },
+    /// A message of a subscribed target, or one not about any target, with
+    /// the same id as on `/events`.
+    Event { id: i64, message: Message },
+    /// Reply to `Subscribe` and `Unsubscribe` with the subscribed targets.
+    Subscribed {
+        request_id: Option<u64>,
+        targets: Vec<String>,
+    },
+    /// Reply to `Check`.
+    Checked {
+        request_id: Option<u64>,
+        observation: Observation,
+    },
+    /// Reply to `Acknowledge`.
+    Acknowledged {
+        request_id: Option<u64>,
+        incident: Incident,
+    },
+    /// Reply to a command that failed or could not be parsed.
+    Error {
+        request_id: Option<u64>,
+        error: String,
+    },
+}
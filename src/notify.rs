@@ -0,0 +1,516 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Utc;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use rocket::serde::json::json;
+use rocket::tokio;
+use rocket::tokio::sync::broadcast::error::RecvError;
+
+use crate::events::EventSender;
+use crate::model::{
+    Config, EmailNotifierDescriptor, Message, MonitoringTargetStatus, Notification,
+    NotificationDelivery, NotificationKind, NotifierDescriptor, NotifierTypeDescriptor,
+    ObservedMonitoringTargetStatus, SmtpTls,
+};
+use crate::templates::Template;
+use crate::{args, db};
+
+const NOTIFIER_TIMEOUT: Duration = Duration::from_secs(10);
+const TITLE_TEMPLATE: &str = "[{{status}}] {{name}}";
+const BODY_TEMPLATE: &str = "{{name}} is {{status}} (was {{previous_status}}).\n{{description}}";
+const EMAIL_BODY_TEMPLATE: &str = "{{name}} is {{status}} (was {{previous_status}}, incident duration {{duration}}).\n\n{{description}}\n\n{{dashboard_url}}";
+const FLAPPING_STARTED_TITLE_TEMPLATE: &str = "[Flapping] {{name}}";
+const FLAPPING_STARTED_BODY_TEMPLATE: &str = "{{name}} is flapping and currently {{status}}. Status changes are not notified until it settles.\n{{description}}";
+const FLAPPING_STOPPED_TITLE_TEMPLATE: &str = "[{{status}}] {{name}} stopped flapping";
+const FLAPPING_STOPPED_BODY_TEMPLATE: &str =
+    "{{name}} stopped flapping and is {{status}}.\n{{description}}";
+
+fn render_title(notifier: &NotifierDescriptor, notification: &Notification) -> String {
+    let default_title = match notification.kind {
+        NotificationKind::FlappingStarted => FLAPPING_STARTED_TITLE_TEMPLATE,
+        NotificationKind::FlappingStopped => FLAPPING_STOPPED_TITLE_TEMPLATE,
+        _ => TITLE_TEMPLATE,
+    };
+    match &notifier.title {
+        Some(title) => title.render(notification),
+        None => Template::parse(default_title).unwrap().render(notification),
+    }
+}
+
+/// Email gets a more verbose default body than the chat notifiers.
+fn render_body(notifier: &NotifierDescriptor, notification: &Notification) -> String {
+    let default_body = match (&notification.kind, &notifier.notifier) {
+        (NotificationKind::FlappingStarted, _) => FLAPPING_STARTED_BODY_TEMPLATE,
+        (NotificationKind::FlappingStopped, _) => FLAPPING_STOPPED_BODY_TEMPLATE,
+        (_, NotifierTypeDescriptor::Email(_)) => EMAIL_BODY_TEMPLATE,
+        _ => BODY_TEMPLATE,
+    };
+    match &notifier.body {
+        Some(body) => body.render(notification),
+        None => Template::parse(default_body).unwrap().render(notification),
+    }
+}
+
+async fn send_email(
+    email: &EmailNotifierDescriptor,
+    title: &str,
+    body: &str,
+) -> Result<(), String> {
+    let mut builder = lettre::Message::builder()
+        .from(
+            email
+                .from
+                .parse::<Mailbox>()
+                .map_err(|error| error.to_string())?,
+        )
+        .subject(title);
+    for recipient in email.to.iter() {
+        builder = builder.to(recipient
+            .parse::<Mailbox>()
+            .map_err(|error| error.to_string())?);
+    }
+    let message = builder
+        .body(body.to_string())
+        .map_err(|error| error.to_string())?;
+
+    let host = &email.host;
+    let mut transport = match email.tls {
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(|error| error.to_string())?,
+        SmtpTls::Tls => {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(host).map_err(|error| error.to_string())?
+        }
+    };
+    transport = transport.timeout(Some(NOTIFIER_TIMEOUT));
+    if let Some(port) = email.port {
+        transport = transport.port(port);
+    }
+    if let (Some(username), Some(password)) = (&email.username, &email.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    transport
+        .build()
+        .send(message)
+        .await
+        .map_err(|error| error.to_string())?;
+    Ok(())
+}
+
+/// Posts the notification as JSON, or the rendered body if the notifier has a
+/// body template.
+async fn send_webhook(
+    url: &str,
+    headers: &HashMap<String, String>,
+    body: Option<String>,
+    notification: &Notification,
+) -> Result<(), reqwest::Error> {
+    let client = reqwest::Client::new();
+    let mut request = client.post(url).timeout(NOTIFIER_TIMEOUT);
+    request = match body {
+        Some(body) => request.body(body),
+        None => request.json(notification),
+    };
+    for (name, value) in headers.iter() {
+        request = request.header(name, value);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Same colours as the dashboard.
+fn status_color(status: &MonitoringTargetStatus) -> u32 {
+    match status {
+        MonitoringTargetStatus::Healthy => 0x5cdd8b,
+        MonitoringTargetStatus::Degraded => 0xffc107,
+        MonitoringTargetStatus::Unhealthy => 0xdc3545,
+        MonitoringTargetStatus::Maintenance => 0x0d6efd,
+        MonitoringTargetStatus::Unknown => 0x6c757d,
+        MonitoringTargetStatus::Pending => 0xadb5bd,
+        MonitoringTargetStatus::Paused => 0x6f42c1,
+        MonitoringTargetStatus::Unreachable => 0xfd7e14,
+    }
+}
+
+async fn send_ntfy(
+    url: &str,
+    topic: &str,
+    token: &Option<String>,
+    title: &str,
+    body: &str,
+    notification: &Notification,
+) -> Result<(), reqwest::Error> {
+    let (priority, tag) = match notification.observed_status.status {
+        MonitoringTargetStatus::Healthy => (3, "white_check_mark"),
+        MonitoringTargetStatus::Maintenance => (2, "wrench"),
+        MonitoringTargetStatus::Pending => (2, "hourglass"),
+        MonitoringTargetStatus::Paused => (2, "pause_button"),
+        MonitoringTargetStatus::Unreachable => (2, "link"),
+        MonitoringTargetStatus::Unknown => (4, "grey_question"),
+        MonitoringTargetStatus::Degraded => (4, "warning"),
+        MonitoringTargetStatus::Unhealthy => (5, "rotating_light"),
+    };
+    let client = reqwest::Client::new();
+    let mut request = client
+        .post(url.trim_end_matches('/'))
+        .timeout(NOTIFIER_TIMEOUT)
+        .json(&json!({
+            "topic": topic,
+            "title": title,
+            "message": body,
+            "priority": priority,
+            "tags": [tag],
+        }));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+async fn send_gotify(
+    url: &str,
+    token: &str,
+    title: &str,
+    body: &str,
+    notification: &Notification,
+) -> Result<(), reqwest::Error> {
+    let priority = match notification.observed_status.status {
+        MonitoringTargetStatus::Healthy
+        | MonitoringTargetStatus::Maintenance
+        | MonitoringTargetStatus::Pending
+        | MonitoringTargetStatus::Paused
+        | MonitoringTargetStatus::Unreachable => 2,
+        MonitoringTargetStatus::Degraded | MonitoringTargetStatus::Unknown => 5,
+        MonitoringTargetStatus::Unhealthy => 8,
+    };
+    let client = reqwest::Client::new();
+    client
+        .post(format!("{}/message", url.trim_end_matches('/')))
+        .timeout(NOTIFIER_TIMEOUT)
+        .header("X-Gotify-Key", token)
+        .json(&json!({
+            "title": title,
+            "message": body,
+            "priority": priority,
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Percent-encodes everything but unreserved characters, so the text can be
+/// used as one segment of a URL path.
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+async fn send_matrix(
+    homeserver: &str,
+    room_id: &str,
+    access_token: &str,
+    title: &str,
+    body: &str,
+    notification: &Notification,
+) -> Result<(), reqwest::Error> {
+    let transaction_id = format!(
+        "observatory-{}-{}-{}",
+        notification.monitoring_target.id,
+        notification.kind.name(),
+        notification
+            .observed_status
+            .timestamp
+            .timestamp_nanos_opt()
+            .unwrap_or_default()
+    );
+    let url = format!(
+        "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+        homeserver.trim_end_matches('/'),
+        encode_path_segment(room_id),
+        encode_path_segment(&transaction_id)
+    );
+    let client = reqwest::Client::new();
+    client
+        .put(url)
+        .timeout(NOTIFIER_TIMEOUT)
+        .bearer_auth(access_token)
+        .json(&json!({
+            "msgtype": "m.text",
+            "body": format!("{}\n{}", title, body),
+            "format": "org.matrix.custom.html",
+            "formatted_body": format!(
+                "<strong><font color=\"#{:06x}\">{}</font></strong><br>{}",
+                status_color(&notification.observed_status.status),
+                escape_html(title),
+                escape_html(body).replace('\n', "<br>")
+            ),
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn send_slack(
+    url: &str,
+    title: &str,
+    body: &str,
+    notification: &Notification,
+) -> Result<(), reqwest::Error> {
+    let client = reqwest::Client::new();
+    client
+        .post(url)
+        .timeout(NOTIFIER_TIMEOUT)
+        .json(&json!({
+            "attachments": [{
+                "color": format!("#{:06x}", status_color(&notification.observed_status.status)),
+                "title": title,
+                "text": body,
+                "ts": notification.observed_status.timestamp.timestamp(),
+            }],
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn send_discord(
+    url: &str,
+    title: &str,
+    body: &str,
+    notification: &Notification,
+) -> Result<(), reqwest::Error> {
+    let client = reqwest::Client::new();
+    client
+        .post(url)
+        .timeout(NOTIFIER_TIMEOUT)
+        .json(&json!({
+            "embeds": [{
+                "color": status_color(&notification.observed_status.status),
+                "title": title,
+                "description": body,
+                "timestamp": notification.observed_status.timestamp.to_rfc3339(),
+            }],
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn send_notification(
+    notifier: &NotifierDescriptor,
+    notification: &Notification,
+) -> Result<(), String> {
+    let title = &render_title(notifier, notification);
+    let body = &render_body(notifier, notification);
+    match &notifier.notifier {
+        NotifierTypeDescriptor::Webhook { url, headers } => {
+            let body = notifier.body.is_some().then(|| body.clone());
+            send_webhook(url, headers, body, notification)
+                .await
+                .map_err(|error| error.to_string())
+        }
+        NotifierTypeDescriptor::Email(email) => send_email(email, title, body).await,
+        NotifierTypeDescriptor::Ntfy { url, topic, token } => {
+            send_ntfy(url, topic, token, title, body, notification)
+                .await
+                .map_err(|error| error.to_string())
+        }
+        NotifierTypeDescriptor::Gotify { url, token } => {
+            send_gotify(url, token, title, body, notification)
+                .await
+                .map_err(|error| error.to_string())
+        }
+        NotifierTypeDescriptor::Matrix {
+            homeserver,
+            room_id,
+            access_token,
+        } => send_matrix(homeserver, room_id, access_token, title, body, notification)
+            .await
+            .map_err(|error| error.to_string()),
+        NotifierTypeDescriptor::Slack { url } => send_slack(url, title, body, notification)
+            .await
+            .map_err(|error| error.to_string()),
+        NotifierTypeDescriptor::Discord { url } => send_discord(url, title, body, notification)
+            .await
+            .map_err(|error| error.to_string()),
+    }
+}
+
+/// Sends the notification, retrying with exponential backoff. Every attempt
+/// is written to the delivery log.
+pub async fn deliver(db_path: PathBuf, notifier: NotifierDescriptor, notification: Notification) {
+    let connection = db::init_db(&db_path).unwrap();
+    let mut delay = Duration::from_secs(notifier.retry_delay);
+    let mut attempt = 0;
+    loop {
+        let result = send_notification(&notifier, &notification).await;
+        let delivery = NotificationDelivery {
+            id: 0,
+            notifier_id: notifier.id.clone(),
+            monitoring_target_id: notification.monitoring_target.id.clone(),
+            timestamp: Utc::now(),
+            attempt,
+            success: result.is_ok(),
+            error: result.err(),
+        };
+        db::add_notification_delivery(&connection, &delivery).unwrap();
+        if delivery.success || attempt >= notifier.retries {
+            break;
+        }
+        attempt += 1;
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+fn is_transition(
+    previous_status: &Option<ObservedMonitoringTargetStatus>,
+    observed_status: &ObservedMonitoringTargetStatus,
+) -> bool {
+    match previous_status {
+        Some(previous_status) => previous_status.status != observed_status.status,
+        None => observed_status.status != MonitoringTargetStatus::Healthy,
+    }
+}
+
+/// Link to the target's details page on the dashboard.
+pub fn dashboard_url(dashboard_url: &Option<String>, id: &str) -> Option<String> {
+    dashboard_url
+        .as_ref()
+        .map(|dashboard_url| format!("{}/?id={}#details", dashboard_url.trim_end_matches('/'), id))
+}
+
+pub fn schedule_notifications(event_sender: &EventSender, config: &Config, args: &args::Args) {
+    let db_path = args.database.clone();
+    let notifiers: Vec<NotifierDescriptor> = config
+        .notifiers
+        .iter()
+        .filter(|notifier| {
+            !config.escalation_policies.iter().any(|policy| {
+                policy
+                    .levels
+                    .iter()
+                    .any(|level| level.notifiers.contains(&notifier.id))
+            })
+        })
+        .cloned()
+        .collect();
+    if notifiers.is_empty() {
+        return;
+    }
+    let dashboard_base_url = args.dashboard_url.clone();
+    let connection = db::init_db(&db_path).unwrap();
+    let mut last_statuses: HashMap<String, ObservedMonitoringTargetStatus> = HashMap::new();
+    let mut flapping: HashSet<String> = HashSet::new();
+    for target in config.targets.iter() {
+        let last_observation =
+            db::get_last_observations(&connection, std::slice::from_ref(&target.id))
+                .unwrap()
+                .into_iter()
+                .next();
+        let state = db::get_target_state(&connection, &target.id).unwrap();
+        if let Some(mut last_observation) = last_observation {
+            if let Some(effective_status) = state.effective_status {
+                last_observation.status = effective_status;
+            }
+            last_statuses.insert(target.id.clone(), last_observation);
+        }
+        if state.flapping {
+            flapping.insert(target.id.clone());
+        }
+    }
+    let mut rx = event_sender.subscribe();
+    tokio::task::spawn(async move {
+        let connection = db::init_db(&db_path).unwrap();
+        loop {
+            let (kind, observation) = match rx.recv().await {
+                Ok(Message::Observation(observation)) => {
+                    (NotificationKind::Transition, observation)
+                }
+                Ok(Message::FlappingStarted(observation)) => {
+                    (NotificationKind::FlappingStarted, observation)
+                }
+                Ok(Message::FlappingStopped(observation)) => {
+                    (NotificationKind::FlappingStopped, observation)
+                }
+                Ok(_) => continue,
+                Err(RecvError::Closed) => break,
+                Err(RecvError::Lagged(_)) => continue,
+            };
+            let target_id = &observation.monitoring_target.id;
+            // Flapping targets get a single notice when they start and stop
+            // flapping instead of one per status change.
+            let previous_status = match kind {
+                NotificationKind::FlappingStarted => {
+                    flapping.insert(target_id.clone());
+                    last_statuses.get(target_id).cloned()
+                }
+                NotificationKind::FlappingStopped => {
+                    flapping.remove(target_id);
+                    last_statuses.get(target_id).cloned()
+                }
+                _ => {
+                    if !observation.observed_status.status.is_notifiable() {
+                        continue;
+                    }
+                    let previous_status = last_statuses
+                        .insert(target_id.clone(), observation.observed_status.clone());
+                    if flapping.contains(target_id)
+                        || !is_transition(&previous_status, &observation.observed_status)
+                    {
+                        continue;
+                    }
+                    previous_status
+                }
+            };
+            if db::is_silenced(&connection, &observation.monitoring_target).unwrap() {
+                continue;
+            }
+            let incident =
+                db::get_last_incident(&connection, &observation.monitoring_target.id).unwrap();
+            let dashboard_url =
+                dashboard_url(&dashboard_base_url, &observation.monitoring_target.id);
+            let notification = Notification {
+                kind,
+                monitoring_target: observation.monitoring_target,
+                previous_status,
+                observed_status: observation.observed_status,
+                incident,
+                dashboard_url,
+            };
+            for notifier in notifiers
+                .iter()
+                .filter(|notifier| notifier.is_attached_to(&notification.monitoring_target))
+            {
+                tokio::task::spawn(deliver(
+                    db_path.clone(),
+                    notifier.clone(),
+                    notification.clone(),
+                ));
+            }
+        }
+    });
+}
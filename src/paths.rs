@@ -1,59 +1,661 @@
 use crate::args::Args;
-use crate::db::{get_last_observations, get_monitoring_target_descriptors, init_db, get_observations};
-use crate::model::{MonitoringTargetDescriptor, Observation, ObservedMonitoringTargetStatus, Message};
+use crate::db::{
+    acknowledge_incident, add_silence, end_silence, get_check_counters, get_events_after,
+    get_incidents, get_last_event_id, get_monitoring_target_descriptors,
+    get_notification_deliveries, get_observations, get_open_incident, get_silence, get_silences,
+    get_target_state, get_target_statuses, get_uptime, init_db, is_paused,
+};
+use crate::events::EventSender;
+use crate::maintenance::get_maintenance_windows;
+use crate::model::{
+    Acknowledgement, AcknowledgementRequest, ClientCommand, ClientRequest, Config, DependencyEdge,
+    DependencyGraph, DependencyNode, Incident, MaintenanceWindow, Message,
+    MonitoringTargetDescriptor, NotificationDelivery, Observation, RecordedMessage, ServerMessage,
+    Silence, TargetStatus, Uptime, WEBSOCKET_PROTOCOL_VERSION,
+};
+use crate::schedule::{SchedulerCommand, Schedulers};
+use chrono::Utc;
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::http::{Accept, ContentType};
+use rocket::request::{self, FromRequest};
+use rocket::response::status::BadRequest;
 use rocket::response::stream::{Event, EventStream};
-use rocket::serde::json::Json;
+use rocket::serde::json::{serde_json, Json};
+use rocket::tokio;
 use rocket::tokio::select;
 use rocket::tokio::sync::broadcast::{error::RecvError, Sender};
-use rocket::{Shutdown, State};
+use rocket::tokio::sync::{mpsc, oneshot};
+use rocket::{Request, Shutdown, State};
+use rocket_ws as ws;
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::path::Path;
 
-#[get("/events")]
-pub async fn events(queue: &State<Sender<Message>>, mut end: Shutdown) -> EventStream![] {
+/// Filters of `/events`, e.g. `?target=a&target=b&status=Unhealthy`. Messages
+/// that are not about a target, e.g. app updates, are always sent.
+#[derive(FromForm)]
+pub struct EventFilter {
+    /// Target ids, all targets if empty.
+    target: Vec<String>,
+    tag: Option<String>,
+    group: Option<String>,
+    /// Statuses of observations, retry attempts and flapping messages, all if
+    /// empty.
+    status: Vec<String>,
+    /// Only send observations that changed the status of their target.
+    transitions: bool,
+}
+
+impl EventFilter {
+    fn matches(&self, recorded: &RecordedMessage, config: &Config) -> bool {
+        let message = &recorded.message;
+        if let Some(id) = message.monitoring_target_id() {
+            if !self.target.is_empty() && !self.target.iter().any(|target| target == id) {
+                return false;
+            }
+            if (self.tag.is_some() || self.group.is_some())
+                && !config.targets.iter().any(|target| {
+                    target.id == id
+                        && target.matches_filter(self.tag.as_deref(), self.group.as_deref())
+                })
+            {
+                return false;
+            }
+        }
+        match message {
+            Message::Observation(observation)
+            | Message::RetryAttempt(observation)
+            | Message::FlappingStarted(observation)
+            | Message::FlappingStopped(observation) => {
+                let status = observation.observed_status.status.to_string();
+                (self.status.is_empty() || self.status.contains(&status))
+                    && (!self.transitions
+                        || recorded.transition
+                        || matches!(
+                            message,
+                            Message::FlappingStarted(_) | Message::FlappingStopped(_)
+                        ))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// The `Last-Event-ID` header a reconnecting event source sends.
+pub struct LastEventId(Option<i64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let id = request
+            .headers()
+            .get_one("Last-Event-ID")
+            .and_then(|id| id.parse().ok());
+        request::Outcome::Success(LastEventId(id))
+    }
+}
+
+fn recorded_event(recorded: &RecordedMessage) -> Event {
+    Event::json(&recorded.message).id(recorded.id.to_string())
+}
+
+#[get("/events?<filter..>")]
+pub async fn events(
+    filter: EventFilter,
+    last_event_id: LastEventId,
+    queue: &State<Sender<RecordedMessage>>,
+    config: &State<Config>,
+    args: &State<Args>,
+    mut end: Shutdown,
+) -> EventStream![] {
+    let connection = init_db(&args.database).unwrap();
+    // Messages are stored before they are sent, so lagging behind replays
+    // from the last one stored before subscribing.
+    let subscribed_after = get_last_event_id(&connection).unwrap();
     let mut rx = queue.subscribe();
+    let config = config.inner().clone();
     EventStream! {
         yield Event::json(&Message::AppUpdate);
+        let mut last_id = last_event_id.0.unwrap_or(subscribed_after);
+        // Messages missed while disconnected are replayed from the database.
+        let mut missed = last_event_id.0;
         loop {
-            let msg = select! {
-                msg = rx.recv() => match msg {
-                    Ok(msg) => msg,
+            if let Some(after) = missed.take() {
+                for recorded in get_events_after(&connection, after).unwrap() {
+                    last_id = recorded.id;
+                    if filter.matches(&recorded, &config) {
+                        yield recorded_event(&recorded);
+                    }
+                }
+            }
+            let recorded = select! {
+                recorded = rx.recv() => match recorded {
+                    Ok(recorded) => recorded,
                     Err(RecvError::Closed) => break,
-                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Lagged(_)) => {
+                        missed = Some(last_id);
+                        continue;
+                    }
                 },
                 _ = &mut end => break,
             };
 
-            yield Event::json(&msg);
+            // Already sent while replaying.
+            if recorded.id <= last_id {
+                continue;
+            }
+            last_id = recorded.id;
+            if filter.matches(&recorded, &config) {
+                yield recorded_event(&recorded);
+            }
         }
     }
 }
 
-#[get("/targets")]
-pub async fn targets(args: &State<Args>) -> Json<Vec<MonitoringTargetDescriptor>> {
+fn server_message(message: &ServerMessage) -> ws::Message {
+    ws::Message::text(serde_json::to_string(message).unwrap())
+}
+
+/// Runs a command of a WebSocket client and sends the reply to `replies`.
+/// Checks run in their own task, so events keep flowing while they take.
+fn handle_client_request(
+    request: ClientRequest,
+    subscription: &mut HashSet<String>,
+    replies: &mpsc::UnboundedSender<ServerMessage>,
+    queue: &EventSender,
+    schedulers: &Schedulers,
+    config: &Config,
+    database: &Path,
+) {
+    let request_id = request.request_id;
+    let error = move |error: &str| ServerMessage::Error {
+        request_id,
+        error: error.to_string(),
+    };
+    let reply = match request.command {
+        ClientCommand::Subscribe { targets } | ClientCommand::Unsubscribe { targets }
+            if targets
+                .iter()
+                .any(|id| !config.targets.iter().any(|target| &target.id == id)) =>
+        {
+            error("Unknown target")
+        }
+        ClientCommand::Subscribe { targets } => {
+            if targets.is_empty() {
+                subscription.extend(config.targets.iter().map(|target| target.id.clone()));
+            } else {
+                subscription.extend(targets);
+            }
+            let mut targets: Vec<String> = subscription.iter().cloned().collect();
+            targets.sort();
+            ServerMessage::Subscribed {
+                request_id,
+                targets,
+            }
+        }
+        ClientCommand::Unsubscribe { targets } => {
+            if targets.is_empty() {
+                subscription.clear();
+            } else {
+                subscription.retain(|id| !targets.contains(id));
+            }
+            let mut targets: Vec<String> = subscription.iter().cloned().collect();
+            targets.sort();
+            ServerMessage::Subscribed {
+                request_id,
+                targets,
+            }
+        }
+        ClientCommand::Check {
+            monitoring_target_id,
+        } => {
+            let replies = replies.clone();
+            let schedulers = schedulers.clone();
+            let database = database.to_path_buf();
+            tokio::task::spawn(async move {
+                let reply = match check_now(&schedulers, &database, &monitoring_target_id).await {
+                    Ok(Some(observation)) => ServerMessage::Checked {
+                        request_id,
+                        observation,
+                    },
+                    Ok(None) => error("Unknown target"),
+                    Err(message) => error(message),
+                };
+                let _ = replies.send(reply);
+            });
+            return;
+        }
+        ClientCommand::Acknowledge {
+            monitoring_target_id,
+            acknowledgement,
+        } => {
+            let connection = init_db(database).unwrap();
+            match acknowledge_open_incident(
+                &connection,
+                queue,
+                &monitoring_target_id,
+                acknowledgement,
+            ) {
+                Some(incident) => ServerMessage::Acknowledged {
+                    request_id,
+                    incident,
+                },
+                None => error("Target has no open incident"),
+            }
+        }
+    };
+    let _ = replies.send(reply);
+}
+
+/// Streams the messages of `/events` for the subscribed targets and accepts
+/// `ClientRequest` commands. See `ServerMessage` for the replies.
+#[get("/ws")]
+pub fn websocket<'r>(
+    ws: ws::WebSocket,
+    recorded_queue: &'r State<Sender<RecordedMessage>>,
+    queue: &'r State<EventSender>,
+    schedulers: &'r State<Schedulers>,
+    config: &'r State<Config>,
+    args: &'r State<Args>,
+    mut end: Shutdown,
+) -> ws::Channel<'r> {
     let database = &args.database;
     let connection = init_db(database).unwrap();
-    let monitoring_targets = get_monitoring_target_descriptors(&connection).unwrap();
+    let subscribed_after = get_last_event_id(&connection).unwrap();
+    let mut rx = recorded_queue.subscribe();
+    ws.channel(move |mut stream| {
+        Box::pin(async move {
+            let mut subscription: HashSet<String> = config
+                .targets
+                .iter()
+                .map(|target| target.id.clone())
+                .collect();
+            let hello = ServerMessage::Hello {
+                version: WEBSOCKET_PROTOCOL_VERSION,
+            };
+            stream.send(server_message(&hello)).await?;
+            let (reply_sender, mut replies) = mpsc::unbounded_channel();
+            let mut last_id = subscribed_after;
+            loop {
+                let recorded = select! {
+                    recorded = rx.recv() => match recorded {
+                        Ok(recorded) => vec![recorded],
+                        Err(RecvError::Closed) => break,
+                        // Messages missed while lagging behind are replayed
+                        // from the database.
+                        Err(RecvError::Lagged(_)) => {
+                            get_events_after(&connection, last_id).unwrap()
+                        }
+                    },
+                    message = stream.next() => {
+                        let text = match message {
+                            Some(Ok(ws::Message::Text(text))) => text,
+                            Some(Ok(ws::Message::Close(_))) | None => break,
+                            Some(Ok(_)) => continue,
+                            Some(Err(error)) => return Err(error),
+                        };
+                        match serde_json::from_str::<ClientRequest>(&text) {
+                            Ok(request) => handle_client_request(
+                                request,
+                                &mut subscription,
+                                &reply_sender,
+                                queue,
+                                schedulers,
+                                config,
+                                database,
+                            ),
+                            Err(error) => {
+                                let _ = reply_sender.send(ServerMessage::Error {
+                                    request_id: None,
+                                    error: error.to_string(),
+                                });
+                            }
+                        }
+                        continue;
+                    },
+                    Some(reply) = replies.recv() => {
+                        stream.send(server_message(&reply)).await?;
+                        continue;
+                    },
+                    _ = &mut end => break,
+                };
+                for recorded in recorded {
+                    if recorded.id <= last_id {
+                        continue;
+                    }
+                    last_id = recorded.id;
+                    let subscribed = recorded
+                        .message
+                        .monitoring_target_id()
+                        .is_none_or(|id| subscription.contains(id));
+                    if subscribed {
+                        let event = ServerMessage::Event {
+                            id: recorded.id,
+                            message: recorded.message,
+                        };
+                        stream.send(server_message(&event)).await?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    })
+}
+
+#[get("/targets?<tag>&<group>")]
+pub async fn targets(
+    tag: Option<&str>,
+    group: Option<&str>,
+    args: &State<Args>,
+) -> Json<Vec<MonitoringTargetDescriptor>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let monitoring_targets = get_monitoring_target_descriptors(&connection)
+        .unwrap()
+        .into_iter()
+        .filter(|target| target.matches_filter(tag, group))
+        .collect();
     Json(monitoring_targets)
 }
 
-#[get("/status/<id>")]
-pub async fn status(
+/// Sends a command to the scheduler task of the target and waits for the
+/// observation it replies with.
+async fn run_command(
+    schedulers: &Schedulers,
+    id: &str,
+    command: fn(oneshot::Sender<Observation>) -> SchedulerCommand,
+) -> Option<Observation> {
+    let scheduler = schedulers.get(id)?;
+    let (reply, observation) = oneshot::channel();
+    scheduler.send(command(reply)).await.ok()?;
+    observation.await.ok()
+}
+
+/// Checks a target now unless it is paused. Returns None for unknown targets.
+async fn check_now(
+    schedulers: &Schedulers,
+    database: &Path,
     id: &str,
+) -> Result<Option<Observation>, &'static str> {
+    let connection = init_db(database).unwrap();
+    if is_paused(&connection, id).unwrap() {
+        return Err("Target is paused");
+    }
+    Ok(run_command(schedulers, id, SchedulerCommand::Check).await)
+}
+
+#[post("/targets/<id>/check")]
+pub async fn check_target(
+    id: &str,
+    schedulers: &State<Schedulers>,
     args: &State<Args>,
-) -> Json<Option<ObservedMonitoringTargetStatus>> {
+) -> Result<Option<Json<Observation>>, BadRequest<&'static str>> {
+    let observation = check_now(schedulers, &args.database, id)
+        .await
+        .map_err(BadRequest)?;
+    Ok(observation.map(Json))
+}
+
+#[post("/targets/<id>/pause")]
+pub async fn pause_target(
+    id: &str,
+    schedulers: &State<Schedulers>,
+    args: &State<Args>,
+) -> Result<Option<Json<Observation>>, BadRequest<&'static str>> {
     let database = &args.database;
     let connection = init_db(database).unwrap();
-    let observed_statuses = get_last_observations(&connection, &vec![id.to_string()]).unwrap();
-    let observed_statuses = observed_statuses.into_iter().next();
-    Json(observed_statuses)
+    if is_paused(&connection, id).unwrap() {
+        return Err(BadRequest("Target is already paused"));
+    }
+    let observation = run_command(schedulers, id, SchedulerCommand::Pause).await;
+    Ok(observation.map(Json))
 }
 
-#[get("/observations/<id>")]
-pub async fn observations(
+#[post("/targets/<id>/resume")]
+pub async fn resume_target(
     id: &str,
+    schedulers: &State<Schedulers>,
+    args: &State<Args>,
+) -> Result<Option<Json<Observation>>, BadRequest<&'static str>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    if schedulers.contains_key(id) && !is_paused(&connection, id).unwrap() {
+        return Err(BadRequest("Target is not paused"));
+    }
+    let observation = run_command(schedulers, id, SchedulerCommand::Resume).await;
+    Ok(observation.map(Json))
+}
+
+#[get("/dependencies")]
+pub async fn dependencies(config: &State<Config>, args: &State<Args>) -> Json<DependencyGraph> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let nodes = config
+        .targets
+        .iter()
+        .map(|target| DependencyNode {
+            id: target.id.clone(),
+            name: target.name.clone(),
+            effective_status: get_target_state(&connection, &target.id)
+                .unwrap()
+                .effective_status,
+        })
+        .collect();
+    let edges = config
+        .targets
+        .iter()
+        .flat_map(|target| {
+            target
+                .depends_on
+                .iter()
+                .map(|dependency_id| DependencyEdge {
+                    monitoring_target_id: target.id.clone(),
+                    depends_on: dependency_id.clone(),
+                })
+        })
+        .collect();
+    Json(DependencyGraph { nodes, edges })
+}
+
+/// Builds the status of every target, or only the target with `id`, that
+/// matches the `tag` and `group` filters.
+fn get_filtered_target_statuses(
+    connection: &Connection,
+    id: Option<&str>,
+    tag: Option<&str>,
+    group: Option<&str>,
+) -> Vec<TargetStatus> {
+    get_target_statuses(connection, id)
+        .unwrap()
+        .into_iter()
+        .filter(|(target, _, _)| target.matches_filter(tag, group))
+        .map(|(target, observed_status, state)| TargetStatus {
+            monitoring_target_id: target.id,
+            observed_status,
+            state,
+        })
+        .collect()
+}
+
+#[get("/status?<tag>&<group>")]
+pub async fn statuses(
+    tag: Option<&str>,
+    group: Option<&str>,
     args: &State<Args>,
-) -> Json<Vec<Observation>> {
+) -> Json<Vec<TargetStatus>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    Json(get_filtered_target_statuses(&connection, None, tag, group))
+}
+
+#[get("/status/<id>")]
+pub async fn status(id: &str, args: &State<Args>) -> Json<Option<TargetStatus>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let status = get_filtered_target_statuses(&connection, Some(id), None, None)
+        .into_iter()
+        .next();
+    Json(status)
+}
+
+/// Exposes the targets to Prometheus. Clients that accept OpenMetrics, like
+/// Prometheus itself, get the OpenMetrics format.
+#[get("/metrics")]
+pub async fn metrics(accept: Option<&Accept>, args: &State<Args>) -> (ContentType, String) {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let statuses = get_target_statuses(&connection, None).unwrap();
+    let counters = get_check_counters(&connection).unwrap();
+    let openmetrics = accept.is_some_and(|accept| {
+        accept
+            .media_types()
+            .any(|media_type| media_type.sub() == "openmetrics-text")
+    });
+    let content_type = if openmetrics {
+        crate::metrics::OPENMETRICS_CONTENT_TYPE
+    } else {
+        crate::metrics::PROMETHEUS_CONTENT_TYPE
+    };
+    (
+        ContentType::parse_flexible(content_type).unwrap(),
+        crate::metrics::render(&statuses, &counters, openmetrics),
+    )
+}
+
+#[get("/observations/<id>")]
+pub async fn observations(id: &str, args: &State<Args>) -> Json<Vec<Observation>> {
     let database = &args.database;
     let connection = init_db(database).unwrap();
     let observations = get_observations(&connection, id).unwrap();
     Json(observations)
 }
+
+#[get("/incidents?<open>")]
+pub async fn incidents(open: Option<bool>, args: &State<Args>) -> Json<Vec<Incident>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let incidents = get_incidents(&connection, None, open.unwrap_or(false)).unwrap();
+    Json(incidents)
+}
+
+#[get("/incidents/<id>?<open>")]
+pub async fn target_incidents(
+    id: &str,
+    open: Option<bool>,
+    args: &State<Args>,
+) -> Json<Vec<Incident>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let incidents = get_incidents(&connection, Some(id), open.unwrap_or(false)).unwrap();
+    Json(incidents)
+}
+
+#[get("/notifications/deliveries?<notifier>")]
+pub async fn notification_deliveries(
+    notifier: Option<&str>,
+    args: &State<Args>,
+) -> Json<Vec<NotificationDelivery>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let deliveries = get_notification_deliveries(&connection, notifier).unwrap();
+    Json(deliveries)
+}
+
+/// Acknowledges the open incident of the target with `id`, if any.
+fn acknowledge_open_incident(
+    connection: &Connection,
+    queue: &EventSender,
+    id: &str,
+    acknowledgement: AcknowledgementRequest,
+) -> Option<Incident> {
+    let mut incident = get_open_incident(connection, id).unwrap()?;
+    let acknowledgement = Acknowledgement {
+        timestamp: Utc::now(),
+        author: acknowledgement.author,
+        comment: acknowledgement.comment,
+    };
+    acknowledge_incident(connection, &incident, &acknowledgement).unwrap();
+    incident.acknowledgement = Some(acknowledgement);
+    queue.send(Message::IncidentAcknowledged(incident.clone()));
+    Some(incident)
+}
+
+#[post("/incidents/<id>/acknowledge", data = "<acknowledgement>")]
+pub async fn acknowledge(
+    id: &str,
+    acknowledgement: Json<AcknowledgementRequest>,
+    queue: &State<EventSender>,
+    args: &State<Args>,
+) -> Option<Json<Incident>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let incident = acknowledge_open_incident(&connection, queue, id, acknowledgement.into_inner())?;
+    Some(Json(incident))
+}
+
+#[get("/silences?<active>")]
+pub async fn silences(active: Option<bool>, args: &State<Args>) -> Json<Vec<Silence>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let at = active.unwrap_or(false).then(Utc::now);
+    let silences = get_silences(&connection, at).unwrap();
+    Json(silences)
+}
+
+#[post("/silences", data = "<silence>")]
+pub async fn create_silence(
+    silence: Json<Silence>,
+    queue: &State<EventSender>,
+    args: &State<Args>,
+) -> Result<Json<Silence>, BadRequest<&'static str>> {
+    let silence = silence.into_inner();
+    if silence.monitoring_target_id.is_some() == silence.group.is_some() {
+        return Err(BadRequest(
+            "Exactly one of monitoring_target_id and group must be set",
+        ));
+    }
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let silence = add_silence(&connection, &silence).unwrap();
+    queue.send(Message::SilenceCreated(silence.clone()));
+    Ok(Json(silence))
+}
+
+#[delete("/silences/<id>")]
+pub async fn delete_silence(
+    id: i64,
+    queue: &State<EventSender>,
+    args: &State<Args>,
+) -> Option<Json<Silence>> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    end_silence(&connection, id).unwrap();
+    let silence = get_silence(&connection, id).unwrap()?;
+    queue.send(Message::SilenceEnded(silence.clone()));
+    Some(Json(silence))
+}
+
+#[get("/maintenance?<active>")]
+pub async fn maintenance_windows(
+    active: Option<bool>,
+    config: &State<Config>,
+) -> Json<Vec<MaintenanceWindow>> {
+    let maintenance_windows = get_maintenance_windows(
+        &config.maintenance_windows,
+        Utc::now(),
+        active.unwrap_or(false),
+    );
+    Json(maintenance_windows)
+}
+
+#[get("/uptime/<id>?<days>")]
+pub async fn uptime(id: &str, days: Option<u32>, args: &State<Args>) -> Json<Uptime> {
+    let database = &args.database;
+    let connection = init_db(database).unwrap();
+    let days = days.unwrap_or(args.observation_retention_duration);
+    let since = Utc::now() - chrono::Duration::days(days as i64);
+    let uptime = get_uptime(&connection, id, since).unwrap();
+    Json(uptime)
+}
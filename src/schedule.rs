@@ -1,14 +1,116 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
 use chrono::Utc;
-use rocket::tokio::sync::broadcast::Sender;
-use rocket::{serde, tokio};
+use rand::Rng;
+use rocket::tokio;
+use rocket::tokio::sync::broadcast::{error::RecvError, Receiver};
+use rocket::tokio::sync::{mpsc, oneshot, Semaphore};
+use rocket::tokio::time::{Instant, Interval, MissedTickBehavior};
+use rusqlite::Connection;
 
+use crate::calendar::{in_active_hours, CronExpression};
+use crate::events::EventSender;
 use crate::model::{
-    CheckedMonitoringTargetStatus, Message, MonitoringTargetDescriptor, MonitoringTargetStatus,
-    MonitoringTargetTypeDescriptor, Observation, ObservedMonitoringTargetStatus,
+    CheckedMonitoringTargetStatus, Config, MaintenanceWindowDescriptor, Message,
+    MonitoringTargetDescriptor, MonitoringTargetStatus, MonitoringTargetTypeDescriptor,
+    Observation, ObservedMonitoringTargetStatus, SchedulingDescriptor,
 };
-use crate::{args, checks::*, db};
+use crate::{args, checks::*, composite, db, flapping, hysteresis, incidents, maintenance};
+
+/// Requests handled by the scheduler task of a target between its runs. They
+/// also cut short the jitter and retry delays of a scheduled run, which is
+/// then dropped.
+pub enum SchedulerCommand {
+    /// Runs the check now without retries and replies with the observation.
+    Check(oneshot::Sender<Observation>),
+    /// Stops running the check and replies with the `Paused` marker.
+    Pause(oneshot::Sender<Observation>),
+    /// Runs the check now without retries, restarts the schedule from there
+    /// and replies with the observation.
+    Resume(oneshot::Sender<Observation>),
+}
+
+/// Control channels of the scheduler tasks by target id.
+pub type Schedulers = HashMap<String, mpsc::Sender<SchedulerCommand>>;
+
+/// Waits for the regular runs of a target, either every `interval`, at the
+/// occurrences of its cron `schedule` or, for composite targets, whenever a
+/// member is observed.
+enum Ticker {
+    Interval(Interval),
+    Cron(CronExpression, chrono_tz::Tz),
+    Members(Receiver<Message>, Vec<String>),
+}
+
+impl Ticker {
+    /// Interval targets start after a random offset of up to `start_spread`
+    /// seconds.
+    fn new(
+        target: &MonitoringTargetDescriptor,
+        start_spread: u64,
+        event_sender: &EventSender,
+    ) -> Ticker {
+        if let MonitoringTargetTypeDescriptor::Composite { members, .. } = &target.target {
+            return Ticker::Members(event_sender.subscribe(), members.clone());
+        }
+        match &target.schedule {
+            Some(schedule) => {
+                Ticker::Cron(schedule.clone(), target.timezone.unwrap_or(chrono_tz::UTC))
+            }
+            None => {
+                let period = Duration::from_secs(target.interval);
+                let mut offset = Duration::ZERO;
+                if start_spread > 0 {
+                    let spread = start_spread.min(target.interval) * 1000;
+                    offset = Duration::from_millis(rand::thread_rng().gen_range(0..spread));
+                }
+                let mut interval = tokio::time::interval_at(Instant::now() + offset, period);
+                // Runs that are missed while retrying are skipped instead of
+                // being caught up, so the schedule keeps its phase.
+                interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                Ticker::Interval(interval)
+            }
+        }
+    }
+
+    /// Restarts the interval from now, cron schedules are not affected.
+    fn reset(&mut self) {
+        if let Ticker::Interval(interval) = self {
+            interval.reset();
+        }
+    }
+
+    async fn tick(&mut self) {
+        match self {
+            Ticker::Interval(interval) => {
+                interval.tick().await;
+            }
+            Ticker::Cron(schedule, timezone) => {
+                let now = Utc::now();
+                match schedule.next_after(now, timezone) {
+                    Some(next) => {
+                        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await
+                    }
+                    None => std::future::pending().await,
+                }
+            }
+            Ticker::Members(receiver, members) => loop {
+                match receiver.recv().await {
+                    Ok(Message::Observation(observation))
+                        if members.contains(&observation.monitoring_target.id) =>
+                    {
+                        break
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(_)) => break,
+                    Err(RecvError::Closed) => std::future::pending().await,
+                }
+            },
+        }
+    }
+}
 
 async fn check_status(target: &MonitoringTargetDescriptor) -> CheckedMonitoringTargetStatus {
     match &target.target {
@@ -16,7 +118,35 @@ async fn check_status(target: &MonitoringTargetDescriptor) -> CheckedMonitoringT
         MonitoringTargetTypeDescriptor::HTTP { url } => check_http_url(url).await,
         MonitoringTargetTypeDescriptor::Ping { target } => check_ping(target).await,
         MonitoringTargetTypeDescriptor::FSSpace { path } => check_fs_space(path).await,
+        MonitoringTargetTypeDescriptor::PrometheusMetric {
+            url,
+            metric,
+            labels,
+            thresholds,
+        } => check_prometheus_metric(url, metric, labels, thresholds).await,
+        // Evaluated from the stored member states in `run_target`.
+        MonitoringTargetTypeDescriptor::Composite { .. } => CheckedMonitoringTargetStatus {
+            status: MonitoringTargetStatus::Unknown,
+            description: "Composite targets are not checked directly".to_string(),
+            metrics: HashMap::new(),
+        },
+    }
+}
+
+/// Backoff stops doubling the retry delay after this many retries.
+const MAX_RETRY_BACKOFF_EXPONENT: u32 = 16;
+
+/// Delay before the given retry, counting from 1.
+fn retry_delay(target: &MonitoringTargetDescriptor, retry: u32) -> Duration {
+    let mut delay = target.retry_interval.unwrap_or(target.interval);
+    if target.retry_backoff {
+        let exponent = (retry - 1).min(MAX_RETRY_BACKOFF_EXPONENT);
+        delay = delay.saturating_mul(2u64.pow(exponent));
     }
+    if target.retry_jitter > 0 {
+        delay = delay.saturating_add(rand::thread_rng().gen_range(0..=target.retry_jitter));
+    }
+    Duration::from_secs(delay)
 }
 
 pub fn schedule_cleanup(args: &args::Args) {
@@ -27,76 +157,332 @@ pub fn schedule_cleanup(args: &args::Args) {
         let connection = db::init_db(&db_path).unwrap();
         loop {
             db::delete_old_observations(&connection, observation_retention_duration).unwrap();
+            db::delete_old_notification_deliveries(&connection, observation_retention_duration)
+                .unwrap();
+            db::delete_old_silences(&connection, observation_retention_duration).unwrap();
+            db::delete_old_events(&connection, observation_retention_duration).unwrap();
             tokio::time::sleep(Duration::from_secs(observation_retention_check_interval)).await;
         }
     });
 }
 
-pub fn schedule_checks(event_sender: Sender<Message>, args: &args::Args) {
-    let db_path = &args.database;
-    let monitoring_targets = if args.config.is_none() {
-        vec![]
+/// Semaphores shared by the scheduler tasks to limit the checks in flight.
+#[derive(Clone)]
+struct CheckLimits {
+    global: Option<Arc<Semaphore>>,
+    per_type: HashMap<String, Arc<Semaphore>>,
+}
+
+impl CheckLimits {
+    fn new(scheduling: &SchedulingDescriptor) -> CheckLimits {
+        CheckLimits {
+            global: scheduling
+                .max_concurrent_checks
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            per_type: scheduling
+                .max_concurrent_checks_per_type
+                .iter()
+                .map(|(check_type, limit)| (check_type.clone(), Arc::new(Semaphore::new(*limit))))
+                .collect(),
+        }
+    }
+}
+
+/// Runs the check as soon as the limits allow it. The timeout only applies
+/// to the check itself, the time spent waiting for a slot is returned
+/// separately, followed by the duration of the check.
+async fn run_check(
+    target: &MonitoringTargetDescriptor,
+    limits: &CheckLimits,
+) -> (CheckedMonitoringTargetStatus, Duration, Duration) {
+    let queued = Instant::now();
+    // The per-type slot comes first, so checks waiting for it do not hold a
+    // global slot that checks of other types could use.
+    let _type_permit = match limits.per_type.get(&target.target.type_name()) {
+        Some(semaphore) => Some(semaphore.acquire().await.unwrap()),
+        None => None,
+    };
+    let _global_permit = match &limits.global {
+        Some(semaphore) => Some(semaphore.acquire().await.unwrap()),
+        None => None,
+    };
+    let queue_time = queued.elapsed();
+    let started = Instant::now();
+    let status_awaitable = check_status(target);
+    let status =
+        match tokio::time::timeout(Duration::from_secs(target.timeout), status_awaitable).await {
+            Ok(observed_status) => observed_status,
+            Err(_) => CheckedMonitoringTargetStatus {
+                status: MonitoringTargetStatus::Unhealthy,
+                description: "Timeout".to_string(),
+                metrics: HashMap::new(),
+            },
+        };
+    (status, queue_time, started.elapsed())
+}
+
+/// The first dependency whose effective status is a problem or that is
+/// unreachable itself.
+fn failing_dependency(
+    connection: &Connection,
+    target: &MonitoringTargetDescriptor,
+) -> Option<(String, MonitoringTargetStatus)> {
+    target.depends_on.iter().find_map(|dependency_id| {
+        let state = db::get_target_state(connection, dependency_id).unwrap();
+        state
+            .effective_status
+            .filter(|status| status.is_problem() || *status == MonitoringTargetStatus::Unreachable)
+            .map(|status| (dependency_id.clone(), status))
+    })
+}
+
+/// Sleeps for the duration unless a command arrives first, which is returned.
+async fn sleep_unless_commanded(
+    duration: Duration,
+    commands: &mut mpsc::Receiver<SchedulerCommand>,
+) -> Option<SchedulerCommand> {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => None,
+        Some(command) = commands.recv() => Some(command),
+    }
+}
+
+/// Runs the check of the target, then stores and broadcasts the result.
+/// Returns the observation with the effective status. Scheduled runs pass the
+/// command queue of the target and retry failed checks. A command that
+/// arrives while waiting for a retry ends the run without a result and is
+/// returned instead. On-demand runs pass `None` and are not retried.
+async fn run_target(
+    target: &MonitoringTargetDescriptor,
+    connection: &mut Connection,
+    event_sender: &EventSender,
+    maintenance_windows: &[MaintenanceWindowDescriptor],
+    limits: &CheckLimits,
+    mut commands: Option<&mut mpsc::Receiver<SchedulerCommand>>,
+) -> Result<Observation, SchedulerCommand> {
+    let max_retries = if commands.is_some() {
+        target.retries
     } else {
-        let content = std::fs::read_to_string(&args.config.as_ref().unwrap()).unwrap();
-        serde::json::from_str::<Vec<MonitoringTargetDescriptor>>(&content).unwrap()
+        0
+    };
+    let mut retries = 0;
+    let (mut status, queue_time, duration) =
+        if let MonitoringTargetTypeDescriptor::Composite { members, rule } = &target.target {
+            let status = composite::evaluate(connection, members, rule).unwrap();
+            (status, Duration::ZERO, Duration::ZERO)
+        } else {
+            loop {
+                let (status, queue_time, duration) = run_check(target, limits).await;
+                if status.status == MonitoringTargetStatus::Healthy || retries == max_retries {
+                    break (status, queue_time, duration);
+                }
+                retries += 1;
+                // Report the failed attempt as pending until the retries are used
+                // up. Retry attempts are not stored as observations.
+                let pending_observation = Observation {
+                    monitoring_target: target.clone(),
+                    observed_status: ObservedMonitoringTargetStatus {
+                        timestamp: Utc::now(),
+                        status: MonitoringTargetStatus::Pending,
+                        description: status.description,
+                        retries,
+                        queue_time: queue_time.as_millis() as u64,
+                        duration: duration.as_millis() as u64,
+                        metrics: status.metrics,
+                    },
+                };
+                event_sender.send(Message::RetryAttempt(pending_observation));
+                if let Some(commands) = commands.as_deref_mut() {
+                    if let Some(command) =
+                        sleep_unless_commanded(retry_delay(target, retries as u32), commands).await
+                    {
+                        return Err(command);
+                    }
+                }
+            }
+        };
+    let failed = status.status.is_problem();
+    let timestamp = Utc::now();
+    if status.status != MonitoringTargetStatus::Healthy
+        && maintenance::in_maintenance(maintenance_windows, target, timestamp)
+    {
+        status.status = MonitoringTargetStatus::Maintenance;
+    } else if status.status != MonitoringTargetStatus::Healthy {
+        if let Some((dependency_id, dependency_status)) = failing_dependency(connection, target) {
+            status.status = MonitoringTargetStatus::Unreachable;
+            status.description = format!(
+                "{} ({} is {})",
+                status.description, dependency_id, dependency_status
+            );
+        }
+    }
+    let observed_status = ObservedMonitoringTargetStatus {
+        timestamp,
+        status: status.status,
+        description: status.description,
+        retries,
+        queue_time: queue_time.as_millis() as u64,
+        duration: duration.as_millis() as u64,
+        metrics: status.metrics,
+    };
+    let observation = Observation {
+        monitoring_target: target.clone(),
+        observed_status,
     };
+    db::add_observation(connection, &observation).unwrap();
+    db::count_check(connection, &target.id, failed, retries).unwrap();
+    // Incidents and notifications follow the effective status.
+    let observation = hysteresis::track_observation(connection, &observation).unwrap();
+    let incident_message = incidents::track_observation(connection, &observation).unwrap();
+    // Sent before the observation, so notifiers already suppress the
+    // transition that started the flapping.
+    if let Some(flapping_message) = flapping::track_observation(connection, &observation).unwrap() {
+        event_sender.send(flapping_message);
+    }
+    let message = Message::Observation(observation.clone());
+    event_sender.send(message);
+    if let Some(incident_message) = incident_message {
+        event_sender.send(incident_message);
+    }
+    Ok(observation)
+}
+
+/// Stores and broadcasts a `Paused` marker, so the gap in the observations
+/// is explained.
+fn pause_target(
+    target: &MonitoringTargetDescriptor,
+    connection: &Connection,
+    event_sender: &EventSender,
+) -> Observation {
+    db::set_paused(connection, &target.id, true).unwrap();
+    let observation = Observation {
+        monitoring_target: target.clone(),
+        observed_status: ObservedMonitoringTargetStatus {
+            timestamp: Utc::now(),
+            status: MonitoringTargetStatus::Paused,
+            description: "Paused".to_string(),
+            retries: 0,
+            queue_time: 0,
+            duration: 0,
+            metrics: HashMap::new(),
+        },
+    };
+    db::add_observation(connection, &observation).unwrap();
+    let observation = hysteresis::track_observation(connection, &observation).unwrap();
+    event_sender.send(Message::Observation(observation.clone()));
+    observation
+}
+
+pub fn schedule_checks(
+    event_sender: EventSender,
+    config: &Config,
+    args: &args::Args,
+) -> Schedulers {
+    let db_path = &args.database;
+    let monitoring_targets = config.targets.clone();
     let connection = db::init_db(db_path).unwrap();
     for target in monitoring_targets.iter() {
         db::create_or_update_monitoring_target(&connection, target).unwrap();
     }
+    let limits = CheckLimits::new(&config.scheduling);
+    let mut schedulers = Schedulers::new();
     for target in monitoring_targets {
+        let (command_sender, mut commands) = mpsc::channel(16);
+        schedulers.insert(target.id.clone(), command_sender);
         let event_sender = event_sender.clone();
+        let maintenance_windows = config.maintenance_windows.clone();
+        let limits = limits.clone();
+        let start_spread = config.scheduling.start_spread;
         let db_path = db_path.to_path_buf();
         tokio::task::spawn(async move {
-            let connection = db::init_db(&db_path).unwrap();
-            let mut tick = tokio::time::interval(Duration::from_secs(target.interval));
+            let mut connection = db::init_db(&db_path).unwrap();
+            let mut ticker = Ticker::new(&target, start_spread, &event_sender);
+            let mut paused = db::is_paused(&connection, &target.id).unwrap();
+            let mut interrupting_command = None;
             loop {
-                tick.tick().await;
-                let mut retries_left = target.retries;
-                let status = loop {
-                    let status_awaitable = check_status(&target);
-                    match tokio::time::timeout(
-                        Duration::from_secs(target.timeout),
-                        status_awaitable,
-                    )
-                    .await
-                    {
-                        Ok(observed_status) => {
-                            if observed_status.status == MonitoringTargetStatus::Healthy {
-                                break observed_status;
-                            }
-                            if retries_left == 0 {
-                                break observed_status;
+                // Commands are handled by this task, so on-demand runs never
+                // overlap with scheduled ones.
+                let command = match interrupting_command.take() {
+                    Some(command) => Some(command),
+                    None => tokio::select! {
+                        _ = ticker.tick(), if !paused => None,
+                        Some(command) = commands.recv() => Some(command),
+                    },
+                };
+                match command {
+                    // Commands that do not fit the current state are dropped.
+                    Some(SchedulerCommand::Check(_)) | Some(SchedulerCommand::Pause(_))
+                        if paused => {}
+                    Some(SchedulerCommand::Resume(_)) if !paused => {}
+                    Some(SchedulerCommand::Pause(reply)) => {
+                        paused = true;
+                        let _ = reply.send(pause_target(&target, &connection, &event_sender));
+                    }
+                    Some(SchedulerCommand::Resume(reply)) => {
+                        paused = false;
+                        db::set_paused(&connection, &target.id, false).unwrap();
+                        let Ok(observation) = run_target(
+                            &target,
+                            &mut connection,
+                            &event_sender,
+                            &maintenance_windows,
+                            &limits,
+                            None,
+                        )
+                        .await
+                        else {
+                            unreachable!("on-demand runs are not retried");
+                        };
+                        ticker.reset();
+                        let _ = reply.send(observation);
+                    }
+                    Some(SchedulerCommand::Check(reply)) => {
+                        let Ok(observation) = run_target(
+                            &target,
+                            &mut connection,
+                            &event_sender,
+                            &maintenance_windows,
+                            &limits,
+                            None,
+                        )
+                        .await
+                        else {
+                            unreachable!("on-demand runs are not retried");
+                        };
+                        let _ = reply.send(observation);
+                    }
+                    None => {
+                        if let Some(active_hours) = &target.active_hours {
+                            let timezone = target.timezone.unwrap_or(chrono_tz::UTC);
+                            if !in_active_hours(active_hours, Utc::now(), &timezone) {
+                                continue;
                             }
-                            retries_left -= 1;
                         }
-                        Err(_) => {
-                            if retries_left == 0 {
-                                break CheckedMonitoringTargetStatus {
-                                    status: MonitoringTargetStatus::Unhealthy,
-                                    description: "Timeout".to_string(),
-                                };
+                        if target.jitter > 0 {
+                            let jitter = rand::thread_rng().gen_range(0..=target.jitter * 1000);
+                            interrupting_command = sleep_unless_commanded(
+                                Duration::from_millis(jitter),
+                                &mut commands,
+                            )
+                            .await;
+                            if interrupting_command.is_some() {
+                                continue;
                             }
-                            retries_left -= 1;
                         }
+                        interrupting_command = run_target(
+                            &target,
+                            &mut connection,
+                            &event_sender,
+                            &maintenance_windows,
+                            &limits,
+                            Some(&mut commands),
+                        )
+                        .await
+                        .err();
                     }
-                    tick.tick().await;
-                };
-                let retries = target.retries - retries_left;
-                let observed_status = ObservedMonitoringTargetStatus {
-                    timestamp: Utc::now(),
-                    status: status.status,
-                    description: status.description,
-                    retries,
-                };
-                let observation = Observation {
-                    monitoring_target: target.clone(),
-                    observed_status,
-                };
-                db::add_observation(&connection, &observation).unwrap();
-                let message = Message::Observation(observation);
-                let _ = event_sender.send(message);
+                }
             }
         });
     }
+    schedulers
 }
@@ -0,0 +1,182 @@
+use std::fmt;
+
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::model::{
+    MonitoringTargetDescriptor, Notification, NotificationKind, ObservedMonitoringTargetStatus,
+};
+
+/// Variables available to templates as `{{variable}}`. The short names are
+/// aliases for the most common fields.
+const VARIABLES: &[&str] = &[
+    "target.id",
+    "target.name",
+    "target.group",
+    "target.type",
+    "target.interval",
+    "target.retries",
+    "target.timeout",
+    "status.status",
+    "status.description",
+    "status.timestamp",
+    "status.retries",
+    "previous_status.status",
+    "previous_status.description",
+    "previous_status.timestamp",
+    "previous_status.retries",
+    "duration",
+    "dashboard_url",
+    "kind",
+    "escalation_level",
+    "id",
+    "name",
+    "status",
+    "previous_status",
+    "description",
+];
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Text(String),
+    Variable(String),
+}
+
+/// A notification title or body. Templates are parsed when the config is
+/// loaded, so unknown variables are rejected at startup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, String> {
+        let mut segments = vec![];
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find("}}") else {
+                return Err(format!("Unclosed variable in template: {}", source));
+            };
+            let variable = rest[start + 2..start + end].trim();
+            if !VARIABLES.contains(&variable) {
+                return Err(format!("Unknown template variable: {}", variable));
+            }
+            segments.push(Segment::Variable(variable.to_string()));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        Ok(Template {
+            source: source.to_string(),
+            segments,
+        })
+    }
+
+    pub fn render(&self, notification: &Notification) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Variable(variable) => render_variable(variable, notification),
+            })
+            .collect()
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        Template::parse(&source)
+    }
+}
+
+impl From<Template> for String {
+    fn from(template: Template) -> Self {
+        template.source
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+pub fn format_duration(duration: chrono::Duration) -> String {
+    let seconds = duration.num_seconds().max(0);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+fn render_target_variable(field: &str, target: &MonitoringTargetDescriptor) -> String {
+    match field {
+        "id" => target.id.clone(),
+        "name" => target.name.clone(),
+        "group" => target.group.clone().unwrap_or_default(),
+        "type" => target.target.type_name(),
+        "interval" => target.interval.to_string(),
+        "retries" => target.retries.to_string(),
+        "timeout" => target.timeout.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn render_status_variable(field: &str, status: Option<&ObservedMonitoringTargetStatus>) -> String {
+    let Some(status) = status else {
+        return match field {
+            "status" => "Unknown".to_string(),
+            _ => String::new(),
+        };
+    };
+    match field {
+        "status" => status.status.to_string(),
+        "description" => status.description.clone(),
+        "timestamp" => status.timestamp.to_rfc3339(),
+        "retries" => status.retries.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn render_variable(variable: &str, notification: &Notification) -> String {
+    let observed_status = Some(&notification.observed_status);
+    let previous_status = notification.previous_status.as_ref();
+    match variable.split_once('.') {
+        Some(("target", field)) => render_target_variable(field, &notification.monitoring_target),
+        Some(("status", field)) => render_status_variable(field, observed_status),
+        Some(("previous_status", field)) => render_status_variable(field, previous_status),
+        _ => match variable {
+            "id" | "name" => render_target_variable(variable, &notification.monitoring_target),
+            "status" => render_status_variable("status", observed_status),
+            "previous_status" => render_status_variable("status", previous_status),
+            "description" => render_status_variable("description", observed_status),
+            "duration" => match &notification.incident {
+                Some(incident) => format_duration(
+                    incident
+                        .end
+                        .unwrap_or(notification.observed_status.timestamp)
+                        - incident.start,
+                ),
+                None => format_duration(chrono::Duration::zero()),
+            },
+            "dashboard_url" => notification.dashboard_url.clone().unwrap_or_default(),
+            "kind" => notification.kind.name().to_string(),
+            "escalation_level" => match notification.kind {
+                NotificationKind::Escalation { level } => level.to_string(),
+                _ => String::new(),
+            },
+            _ => String::new(),
+        },
+    }
+}
@@ -2,6 +2,11 @@ COLORS = {
   Healthy: "#5cdd8b",
   Unhealthy: "#dc3545",
   Degraded: "#ffc107",
+  Maintenance: "#0d6efd",
+  Unknown: "#6c757d",
+  Pending: "#adb5bd",
+  Paused: "#6f42c1",
+  Unreachable: "#fd7e14",
 };
 
 class Route {
@@ -63,6 +68,18 @@ class Router {
   }
 }
 
+// Keeps the `tag` and `group` filters of the page, e.g. `/?tag=prod`, to
+// show a dashboard for a subset of the targets.
+function target_filter() {
+  let params = new URLSearchParams(location.search);
+  let filter = new URLSearchParams();
+  for (let key of ["tag", "group"]) {
+    if (params.has(key)) filter.set(key, params.get(key));
+  }
+  let query = filter.toString();
+  return query === "" ? "" : "?" + query;
+}
+
 class ObservatoryApiClient {
   static async target_status(target_id) {
     return await fetch("/status/" + target_id).then((response) =>
@@ -70,8 +87,16 @@ class ObservatoryApiClient {
     );
   }
 
+  static async target_statuses() {
+    return await fetch("/status" + target_filter()).then((response) =>
+      response.json(),
+    );
+  }
+
   static async targets() {
-    return await fetch("/targets").then((response) => response.json());
+    return await fetch("/targets" + target_filter()).then((response) =>
+      response.json(),
+    );
   }
 
   static async observations(target_id) {
@@ -124,7 +149,17 @@ class OverviewRoute extends Route {
     } else {
       let timestamp = moment.utc(status.timestamp);
       let formatted_timestamp = timestamp.format("YYYY-MM-DD HH:mm");
-      tooltip = "Last checked: " + formatted_timestamp;
+      if (status.status == "Pending") {
+        tooltip = "Pending: " + status.description;
+      } else {
+        tooltip = "Last checked: " + formatted_timestamp;
+      }
+      if (status.flapping) {
+        tooltip += " (flapping)";
+      }
+      if (status.retrying != null) {
+        tooltip += " (retrying: " + status.retrying.description + ")";
+      }
       color = COLORS[status.status];
       description = status.description;
     }
@@ -157,14 +192,27 @@ class OverviewRoute extends Route {
     let targets = await ObservatoryApiClient.targets();
     // sort targets by name
     targets.sort((a, b) => a.name.localeCompare(b.name));
+    let statuses = {};
+    for (let status of await ObservatoryApiClient.target_statuses()) {
+      statuses[status.monitoring_target_id] = status;
+    }
     this.children = [];
     for (let target of targets) {
-      let status = await ObservatoryApiClient.target_status(target.id);
+      let status = statuses[target.id] ?? null;
+      // Targets that were not checked yet have no observation.
+      if (status != null && status.timestamp == null) {
+        status = null;
+      }
+      if (status != null && status.effective_status != null) {
+        status.status = status.effective_status;
+      }
       let target_dom = this.build_target_html(target, status);
       this.children.push(target_dom);
       this.targets_by_id[target.id] = {
         target: target,
         dom: target_dom,
+        status: status,
+        flapping: status != null && status.flapping,
       };
     }
   }
@@ -178,7 +226,38 @@ class OverviewRoute extends Route {
         return;
       }
       let target = this.targets_by_id[data.monitoring_target.id];
-      this.update_target_html(target.dom, target.target, data.observed_status);
+      target.status = data.observed_status;
+      this.update_target_html(target.dom, target.target, {
+        ...data.observed_status,
+        flapping: target.flapping,
+      });
+    } else if (data.type == "RetryAttempt") {
+      // Retries keep the confirmed status, they only show up in the tooltip.
+      if (!(data.monitoring_target.id in this.targets_by_id)) {
+        return;
+      }
+      let target = this.targets_by_id[data.monitoring_target.id];
+      if (target.status == null) {
+        return;
+      }
+      this.update_target_html(target.dom, target.target, {
+        ...target.status,
+        flapping: target.flapping,
+        retrying: data.observed_status,
+      });
+    } else if (
+      data.type == "FlappingStarted" ||
+      data.type == "FlappingStopped"
+    ) {
+      if (!(data.monitoring_target.id in this.targets_by_id)) {
+        return;
+      }
+      let target = this.targets_by_id[data.monitoring_target.id];
+      target.flapping = data.type == "FlappingStarted";
+      this.update_target_html(target.dom, target.target, {
+        ...data.observed_status,
+        flapping: target.flapping,
+      });
     }
   }
 }
@@ -256,7 +335,7 @@ let router = new Router({
   details: new DetailsRoute(),
 });
 
-let event_source = new EventSource("/events");
+let event_source = new EventSource("/events" + target_filter());
 event_source.onmessage = function (event) {
   let data = JSON.parse(event.data);
   router.current_route.on_event(data);